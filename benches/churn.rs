@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::pin::Pin;
+use streamunordered::*;
+
+const STREAMS: usize = 100;
+
+/// Push a batch of streams, poll them all once, and then remove them again.
+///
+/// The streams are channel receivers, so their wakers are held by the channels until the senders
+/// are dropped after the streams have been removed.
+fn churn(s: &mut StreamUnordered<mpsc::UnboundedReceiver<()>>) {
+    let (senders, tokens): (Vec<_>, Vec<_>) = (0..STREAMS)
+        .map(|_| {
+            let (tx, rx) = mpsc::unbounded();
+            (tx, s.push(rx))
+        })
+        .unzip();
+    assert!(s.next().now_or_never().is_none());
+    for token in tokens {
+        Pin::new(&mut *s).remove(token);
+    }
+    drop(senders);
+}
+
+fn push_remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("churn");
+    for &limit in &[0, STREAMS] {
+        group.bench_with_input(
+            BenchmarkId::new("recycle_limit", limit),
+            &limit,
+            |b, &limit| {
+                let mut s = StreamUnordered::new();
+                s.set_recycle_limit(limit);
+                b.iter(|| churn(&mut s))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, push_remove);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use streamunordered::*;
+
+/// A stream that, every other time it is polled, wakes itself up and returns `Pending`.
+///
+/// This means that every item it yields costs one wake-up and two polls.
+struct Ping(bool);
+
+impl Stream for Ping {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0 = !self.0;
+        if self.0 {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Ready(Some(()))
+        }
+    }
+}
+
+const ITEMS: usize = 10_000;
+
+fn wake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wake");
+    for &streams in &[1, 100, 1000] {
+        group.bench_with_input(
+            BenchmarkId::new("StreamUnordered", streams),
+            &streams,
+            |b, &streams| {
+                b.iter(|| {
+                    let s: StreamUnordered<_> = (0..streams).map(|_| Ping(false)).collect();
+                    futures::executor::block_on(s.take(ITEMS).for_each(|_| async {}))
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("LocalStreamUnordered", streams),
+            &streams,
+            |b, &streams| {
+                b.iter(|| {
+                    let s: LocalStreamUnordered<_> = (0..streams).map(|_| Ping(false)).collect();
+                    futures::executor::block_on(s.take(ITEMS).for_each(|_| async {}))
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, wake);
+criterion_main!(benches);
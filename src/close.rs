@@ -0,0 +1,457 @@
+//! Shutting down a set of streams that are also sinks.
+
+use super::task::{Task, CLOSED, PARKED};
+use super::StreamUnordered;
+use crate::ready_to_run_queue::Dequeue;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::Ordering::SeqCst;
+use futures_core::task::{Context, Poll};
+use futures_sink::Sink;
+use futures_util::future::{self, Pending};
+
+/// The reason a sink in a [`StreamUnordered`] did not close cleanly.
+///
+/// See [`StreamUnordered::close_all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseError<E> {
+    /// Closing the sink returned an error.
+    Sink(E),
+    /// The deadline passed before the sink finished closing.
+    TimedOut,
+}
+
+/// Future for [`StreamUnordered::close_all`] and [`StreamUnordered::close_all_until`].
+///
+/// Resolves to the tokens of the sinks that did not close cleanly.
+#[must_use = "futures do nothing unless polled"]
+pub struct CloseAll<'a, S, T, D = Pending<()>>
+where
+    S: Sink<T>,
+{
+    set: &'a mut StreamUnordered<S>,
+    deadline: D,
+    failed: Vec<(usize, CloseError<S::Error>)>,
+    _item: PhantomData<fn(T)>,
+}
+
+// Only the deadline is pinned structurally.
+impl<'a, S, T, D> Unpin for CloseAll<'a, S, T, D>
+where
+    S: Sink<T>,
+    D: Unpin,
+{
+}
+
+impl<'a, S, T, D> Future for CloseAll<'a, S, T, D>
+where
+    S: Sink<T>,
+    D: Future<Output = ()>,
+{
+    type Output = Vec<(usize, CloseError<S::Error>)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: the deadline is never moved out of `self`, and none of the other fields are
+        // pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            match Pin::new(&mut *this.set).poll_close_all(cx) {
+                Poll::Ready(Some((token, e))) => this.failed.push((token, CloseError::Sink(e))),
+                Poll::Ready(None) => return Poll::Ready(mem::take(&mut this.failed)),
+                Poll::Pending => break,
+            }
+        }
+
+        let deadline = unsafe { Pin::new_unchecked(&mut this.deadline) };
+        if deadline.poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        for (token, &task) in this.set.by_id.iter() {
+            // we know that by_id only references valid tasks, and the task's
+            // flags are only ever accessed on the thread that owns StreamUnordered.
+            if token != 0 && !unsafe { (*task).has(CLOSED) } {
+                this.failed.push((token, CloseError::TimedOut));
+            }
+        }
+        Poll::Ready(mem::take(&mut this.failed))
+    }
+}
+
+impl<S> StreamUnordered<S> {
+    /// Closes every stream in the set that is also a [`Sink`].
+    ///
+    /// Once this has been called, the set stops reading from its streams:
+    /// [`StreamUnordered::poll_next`](futures_core::Stream::poll_next) always returns `None`,
+    /// until the set is emptied with [`StreamUnordered::clear`].
+    /// Instead, all the sinks are flushed and closed concurrently, with each one only being
+    /// polled again when its own waker is woken. Streams that are paused or finished are closed
+    /// too. The streams stay in the set once closed, and any stream pushed later is closed on the
+    /// next call.
+    ///
+    /// Returns `Some` with the token of a sink that failed to close along with its error, or
+    /// `None` once every sink has been closed. A sink that failed to close is not polled again.
+    pub fn poll_close_all<T>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(usize, S::Error)>>
+    where
+        S: Sink<T>,
+    {
+        self.ready_to_run_queue.waker.register(cx.waker());
+
+        if !self.closing {
+            self.closing = true;
+
+            // Make sure every task ends up in the ready to run queue, since we
+            // have to close even the streams that were not going to be polled.
+            let mut task = self.head_all;
+            while !task.is_null() {
+                // we know that the linked list only references valid tasks, and
+                // the task's flags are only ever accessed on the thread that owns StreamUnordered.
+                unsafe {
+                    if (*task).has(PARKED) {
+                        // Parked tasks are not in the queue, but their queued
+                        // flag is set, so we have to put them back ourselves.
+                        (*task).set(PARKED, false);
+                        self.ready_to_run_queue.enqueue(task);
+                    } else {
+                        let arc = mem::ManuallyDrop::new(Arc::from_raw(task));
+                        Task::schedule(&arc);
+                    }
+                    task = *(*task).next_all.get();
+                }
+            }
+        }
+
+        loop {
+            // Safety: &mut self guarantees the mutual exclusion `dequeue`
+            // expects
+            let task = match unsafe { self.ready_to_run_queue.dequeue() } {
+                Dequeue::Empty => {
+                    let mut task = self.head_all;
+                    while !task.is_null() {
+                        // we know that the linked list only references valid tasks
+                        unsafe {
+                            if !(*task).has(CLOSED) {
+                                return Poll::Pending;
+                            }
+                            task = *(*task).next_all.get();
+                        }
+                    }
+                    return Poll::Ready(None);
+                }
+                Dequeue::Inconsistent => {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Dequeue::Data(task) => task,
+            };
+
+            // Safety: `task` is a valid pointer, and we are the only thread
+            // that accesses the `UnsafeCell` that contains the stream
+            let stream = match unsafe { &mut *(*task).stream.get() } {
+                Some(stream) => stream,
+                None => {
+                    // The task was released while in the queue, just like in
+                    // `poll_next`, so we now own its reference count.
+                    let task = unsafe { Arc::from_raw(task) };
+                    self.recycle_task(task);
+                    continue;
+                }
+            };
+
+            // Safety: we only ever access the task's flags on the thread
+            // that owns StreamUnordered.
+            unsafe {
+                if (*task).has(CLOSED) {
+                    // We don't care about wake-ups once the sink is closed.
+                    (*task).set(PARKED, true);
+                    continue;
+                }
+
+                // Unset queued flag so that we get woken if the sink wakes its
+                // waker while we are closing it.
+                let prev = (*task).queued.swap(false, SeqCst);
+                assert!(prev);
+            }
+
+            // Safety: `task` is a valid pointer
+            let task = unsafe { self.unlink(task) };
+
+            // Just like in `poll_next`, the task is released if closing the
+            // sink panics. See the `Bomb` there.
+            struct Bomb<'a, S> {
+                queue: &'a mut StreamUnordered<S>,
+                task: Option<Arc<Task<S>>>,
+            }
+
+            impl<S> Drop for Bomb<'_, S> {
+                fn drop(&mut self) {
+                    if let Some(task) = self.task.take() {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(parent: &task.span, "sink panicked while closing");
+                        let token = task.id;
+                        self.queue.release_task(task);
+                        self.queue.notify(|o| o.on_remove(token));
+                    }
+                }
+            }
+
+            let mut bomb = Bomb {
+                task: Some(task),
+                queue: &mut *self,
+            };
+
+            // Safety: we never move the stream once it is in the set.
+            let res = {
+                let waker = Task::waker_ref(bomb.task.as_ref().unwrap());
+                let mut cx = Context::from_waker(&waker);
+                unsafe { Pin::new_unchecked(stream) }.poll_close(&mut cx)
+            };
+
+            let task = bomb.task.take().unwrap();
+            let res = match res {
+                Poll::Pending => {
+                    bomb.queue.link(task);
+                    continue;
+                }
+                Poll::Ready(res) => res,
+            };
+
+            // Safety: we only ever access the task's flags on the thread
+            // that owns StreamUnordered.
+            unsafe {
+                task.set(CLOSED, true);
+                if !task.queued.swap(true, SeqCst) {
+                    // Absorb any further wake-ups, like for finished streams.
+                    task.set(PARKED, true);
+                }
+            }
+
+            #[cfg(feature = "tracing")]
+            if res.is_err() {
+                tracing::debug!(parent: &task.span, "sink failed to close");
+            }
+            let id = task.id;
+            bomb.queue.link(task);
+            if let Err(e) = res {
+                return Poll::Ready(Some((id, e)));
+            }
+        }
+    }
+
+    /// Closes every stream in the set that is also a [`Sink`].
+    ///
+    /// The returned future resolves once every sink has been closed, and yields the tokens of
+    /// those that did not close cleanly. See [`StreamUnordered::poll_close_all`] for details.
+    pub fn close_all<T>(&mut self) -> CloseAll<'_, S, T>
+    where
+        S: Sink<T>,
+    {
+        self.close_all_until(future::pending())
+    }
+
+    /// Like [`StreamUnordered::close_all`], but gives up once `deadline` resolves.
+    ///
+    /// The sinks that have not finished closing by then are reported with
+    /// [`CloseError::TimedOut`], and are left as they are. Dropping the set will drop them.
+    pub fn close_all_until<T, D>(&mut self, deadline: D) -> CloseAll<'_, S, T, D>
+    where
+        S: Sink<T>,
+        D: Future<Output = ()>,
+    {
+        CloseAll {
+            set: self,
+            deadline,
+            failed: Vec::new(),
+            _item: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_core::stream::FusedStream;
+    use futures_util::future::FutureExt;
+    use futures_util::stream::StreamExt;
+    use futures_util::task::noop_waker_ref;
+
+    /// A sink that takes `polls` calls to `poll_close` to close, waking itself up in between.
+    struct Slow {
+        polls: usize,
+        fail: bool,
+    }
+
+    impl Sink<()> for Slow {
+        type Error = &'static str;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: ()) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            if self.polls == usize::MAX {
+                // never closes, and never wakes
+                return Poll::Pending;
+            }
+            if self.polls > 0 {
+                self.polls -= 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if self.fail {
+                Poll::Ready(Err("boom"))
+            } else {
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+
+    impl futures_core::Stream for Slow {
+        type Item = ();
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<()>> {
+            Poll::Ready(Some(()))
+        }
+    }
+
+    #[test]
+    fn close_all() {
+        let mut s = StreamUnordered::new();
+        let a = s.push(Slow {
+            polls: 0,
+            fail: false,
+        });
+        let b = s.push(Slow {
+            polls: 3,
+            fail: true,
+        });
+        let c = s.push(Slow {
+            polls: 2,
+            fail: false,
+        });
+        assert!(s.next().now_or_never().is_some());
+        assert!(s.pause(c));
+
+        let failed = s.close_all().now_or_never().unwrap();
+        assert_eq!(failed, vec![(b, CloseError::Sink("boom"))]);
+        for t in [a, b, c] {
+            assert!(unsafe { (*s.by_id[t]).has(CLOSED) });
+        }
+
+        // no more reading once closing has begun
+        assert!(s.is_terminated());
+        assert!(matches!(s.next().now_or_never(), Some(None)));
+    }
+
+    #[test]
+    fn close_all_until() {
+        let mut s = StreamUnordered::new();
+        let a = s.push(Slow {
+            polls: usize::MAX,
+            fail: false,
+        });
+        s.push(Slow {
+            polls: 1,
+            fail: false,
+        });
+
+        let failed = s.close_all_until(future::ready(())).now_or_never().unwrap();
+        assert_eq!(failed, vec![(a, CloseError::TimedOut)]);
+
+        // the stuck sink is still there, and a new one is closed along with it
+        let d = s.push(Slow {
+            polls: 0,
+            fail: false,
+        });
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut s).poll_close_all(&mut cx).is_pending());
+        assert!(unsafe { (*s.by_id[d]).has(CLOSED) });
+    }
+
+    #[test]
+    fn clear_after_close() {
+        let mut s = StreamUnordered::new();
+        s.push(Slow {
+            polls: usize::MAX,
+            fail: false,
+        });
+
+        // the deadline need not be `Unpin`
+        let timeout = async {};
+        assert_eq!(s.close_all_until(timeout).now_or_never().unwrap().len(), 1);
+        assert!(s.is_terminated());
+
+        // a cleared set can be used again
+        s.clear();
+        assert!(!s.is_terminated());
+        let a = s.push(Slow {
+            polls: 0,
+            fail: false,
+        });
+        assert!(matches!(s.next().now_or_never(), Some(Some((_, t))) if t == a));
+    }
+
+    #[test]
+    fn panic_while_closing() {
+        struct Panics;
+
+        impl Sink<()> for Panics {
+            type Error = ();
+
+            fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn start_send(self: Pin<&mut Self>, _: ()) -> Result<(), ()> {
+                Ok(())
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+                panic!("boom");
+            }
+        }
+
+        impl futures_core::Stream for Panics {
+            type Item = ();
+
+            fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<()>> {
+                Poll::Pending
+            }
+        }
+
+        let mut s = StreamUnordered::new();
+        let a = s.push(Panics);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Pin::new(&mut s).poll_close_all(&mut cx)
+        }));
+        assert!(res.is_err());
+
+        // the panicking sink was dropped, and closing carries on without it
+        assert!(s.get(a).is_none());
+        assert_eq!(s.len(), 0);
+        assert!(Pin::new(&mut s).poll_close_all(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,728 @@
+//! A set of connections that are both read from and written to.
+
+use super::{StreamUnordered, StreamYield};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug};
+use core::pin::Pin;
+use core::task::Waker;
+use futures_core::stream::Stream;
+use futures_core::task::{Context, Poll};
+use futures_sink::Sink;
+
+/// A set of streams that are also [`Sink`]s, like network connections.
+///
+/// Each stream has a bounded outbound queue that items are added to with
+/// [`DuplexUnordered::send_to`]. Whenever the set is polled, it moves queued items into the
+/// sinks and flushes them, but only for streams that actually have output pending. Items read
+/// from the streams are yielded along with their token, just like for [`StreamUnordered`].
+///
+/// A stream that yields `None` is only removed from the set once its queued output has been
+/// flushed and its sink has been closed, at which point [`DuplexYield::Closed`] is yielded for
+/// it.
+///
+/// What happens when an item is sent to a stream whose queue is full is decided by the set's
+/// [`OverflowPolicy`]. Every item that is dropped because of it is also reported with
+/// [`DuplexYield::Overflow`].
+pub struct DuplexUnordered<S, T> {
+    streams: StreamUnordered<S>,
+    outbound: Vec<Outbound<T>>,
+    pending: Vec<usize>,
+    limit: usize,
+    policy: OverflowPolicy,
+    overflows: VecDeque<(usize, OverflowPolicy)>,
+}
+
+/// What [`DuplexUnordered::send_to`] does when the stream's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Hand the item back in [`SendError::Full`].
+    ///
+    /// Use [`DuplexUnordered::poll_send_ready`] to wait until there is room again. Since nothing
+    /// is dropped, this overflow is not also reported with [`DuplexYield::Overflow`]. This is the
+    /// default.
+    #[default]
+    Block,
+    /// Drop the oldest item in the queue to make room for the new one.
+    DropOldest,
+    /// Drop the new item.
+    DropNewest,
+    /// Remove the stream from the set, and hand the item back in [`SendError::Disconnected`].
+    ///
+    /// Since the stream is gone, this overflow is not also reported with
+    /// [`DuplexYield::Overflow`].
+    Disconnect,
+}
+
+struct Outbound<T> {
+    queue: VecDeque<T>,
+    // The most items the queue may hold.
+    limit: usize,
+    // The task waiting in `DuplexUnordered::poll_send_ready`, if any.
+    blocked: Option<Waker>,
+    // Items have been given to the sink, but it has not been flushed since.
+    unflushed: bool,
+    // The token is in `DuplexUnordered::pending`.
+    pending: bool,
+    // The stream has yielded `None`.
+    read_done: bool,
+}
+
+impl<T> Outbound<T> {
+    fn new(limit: usize) -> Self {
+        Outbound {
+            queue: VecDeque::new(),
+            limit,
+            blocked: None,
+            unflushed: false,
+            pending: false,
+            read_done: false,
+        }
+    }
+}
+
+/// What a [`DuplexUnordered`] yields for a given stream.
+pub enum DuplexYield<I, E> {
+    /// The stream yielded an item.
+    Item(I),
+    /// Sending to, flushing or closing the sink failed, and the stream has been removed from the
+    /// set.
+    SendError(E),
+    /// The stream has finished, all its output has been flushed, its sink has been closed, and it
+    /// has been removed from the set. The token may be reused.
+    Closed,
+    /// An item was sent to the stream while its outbound queue was full, and the given policy
+    /// dropped an item. This is only ever yielded for [`OverflowPolicy::DropOldest`] and
+    /// [`OverflowPolicy::DropNewest`].
+    Overflow(OverflowPolicy),
+}
+
+impl<I: Debug, E: Debug> Debug for DuplexYield<I, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplexYield::Item(i) => f.debug_tuple("Item").field(i).finish(),
+            DuplexYield::SendError(e) => f.debug_tuple("SendError").field(e).finish(),
+            DuplexYield::Closed => f.debug_tuple("Closed").finish(),
+            DuplexYield::Overflow(p) => f.debug_tuple("Overflow").field(p).finish(),
+        }
+    }
+}
+
+type Event<S, T> = (
+    DuplexYield<<S as Stream>::Item, <S as Sink<T>>::Error>,
+    usize,
+);
+
+/// The error returned by [`DuplexUnordered::send_to`], which hands back the item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The stream's outbound queue is full.
+    Full(T),
+    /// There is no stream with the given token.
+    Vacant(T),
+    /// The stream's outbound queue was full, and the stream was removed from the set because of
+    /// [`OverflowPolicy::Disconnect`].
+    Disconnected(T),
+    /// The stream has finished, and its sink is being closed, so it takes no more items.
+    Closing(T),
+}
+
+impl<T> SendError<T> {
+    /// Returns the item that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Full(t)
+            | SendError::Vacant(t)
+            | SendError::Disconnected(t)
+            | SendError::Closing(t) => t,
+        }
+    }
+}
+
+impl<S: Stream, T> DuplexUnordered<S, T> {
+    /// Constructs a new, empty [`DuplexUnordered`] where each stream can have at most `limit`
+    /// items queued for sending by default.
+    pub fn new(limit: usize) -> Self {
+        DuplexUnordered {
+            streams: StreamUnordered::new(),
+            outbound: Vec::new(),
+            pending: Vec::new(),
+            limit,
+            policy: OverflowPolicy::default(),
+            overflows: VecDeque::new(),
+        }
+    }
+}
+
+impl<S, T> DuplexUnordered<S, T> {
+    /// Returns the number of streams in the set.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Returns `true` if the set contains no streams.
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Push a stream into the set, and return its token.
+    ///
+    /// See [`StreamUnordered::push`].
+    pub fn push(&mut self, stream: S) -> usize {
+        let token = self.streams.push(stream);
+        if self.outbound.len() <= token {
+            let limit = self.limit;
+            self.outbound
+                .resize_with(token + 1, || Outbound::new(limit));
+        }
+        self.outbound[token] = Outbound::new(self.limit);
+        token
+    }
+
+    /// Removes the stream with the given token, along with any output still queued for it.
+    ///
+    /// Overflows that have not been reported for the stream yet are discarded, so they cannot be
+    /// mistaken for overflows of a stream that is later given the same token.
+    ///
+    /// Returns `false` if there is no stream with the given token.
+    pub fn remove(&mut self, token: usize) -> bool {
+        if !Pin::new(&mut self.streams).remove(token) {
+            return false;
+        }
+        if self.outbound[token].pending {
+            self.pending.retain(|&t| t != token);
+        }
+        self.overflows.retain(|&(t, _)| t != token);
+        if let Some(w) = self.outbound[token].blocked.take() {
+            w.wake();
+        }
+        self.outbound[token] = Outbound::new(self.limit);
+        true
+    }
+
+    /// Returns what happens when an item is sent to a stream whose outbound queue is full.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Sets what happens when an item is sent to a stream whose outbound queue is full.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns how many items may be queued for sending to the stream with the given token.
+    pub fn limit(&self, token: usize) -> Option<usize> {
+        self.streams.get(token)?;
+        Some(self.outbound[token].limit)
+    }
+
+    /// Sets how many items may be queued for sending to the stream with the given token.
+    ///
+    /// Items that are already queued are kept even if there are more of them than the new limit.
+    /// Returns `false` if there is no stream with the given token.
+    pub fn set_limit(&mut self, token: usize, limit: usize) -> bool {
+        if self.streams.get(token).is_none() {
+            return false;
+        }
+        let out = &mut self.outbound[token];
+        out.limit = limit;
+        if out.queue.len() < limit {
+            if let Some(w) = out.blocked.take() {
+                w.wake();
+            }
+        }
+        true
+    }
+
+    /// Checks whether an item can be sent to the stream with the given token without
+    /// overflowing its outbound queue.
+    ///
+    /// If not, the current task is woken once there is room. Returns `Ready(false)` if there is
+    /// no stream with the given token (which includes if it is removed while waiting), or if the
+    /// stream has finished and no longer takes items.
+    pub fn poll_send_ready(&mut self, token: usize, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.streams.get(token).is_none() {
+            return Poll::Ready(false);
+        }
+
+        let out = &mut self.outbound[token];
+        if out.read_done {
+            Poll::Ready(false)
+        } else if out.queue.len() < out.limit {
+            Poll::Ready(true)
+        } else {
+            out.blocked = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Returns a reference to the stream with the given token.
+    pub fn get(&self, token: usize) -> Option<&S> {
+        self.streams.get(token)
+    }
+
+    /// Returns a mutable reference to the stream with the given token.
+    pub fn get_mut(&mut self, token: usize) -> Option<&mut S>
+    where
+        S: Unpin,
+    {
+        self.streams.get_mut(token)
+    }
+
+    /// Returns the number of items queued for sending to the stream with the given token.
+    pub fn queued(&self, token: usize) -> Option<usize> {
+        self.streams.get(token)?;
+        Some(self.outbound[token].queue.len())
+    }
+
+    /// Queues an item to be sent to the stream with the given token.
+    ///
+    /// The item is given to the stream's sink the next time the set is polled, once the sink is
+    /// ready for it. If the stream's outbound queue is full, the set's [`OverflowPolicy`] decides
+    /// what happens.
+    ///
+    /// Once the stream has finished, its remaining output is flushed and its sink is closed, so
+    /// it takes no more items, and they are handed back in [`SendError::Closing`].
+    pub fn send_to(&mut self, token: usize, item: T) -> Result<(), SendError<T>> {
+        if self.streams.get(token).is_none() {
+            return Err(SendError::Vacant(item));
+        }
+
+        let out = &mut self.outbound[token];
+        if out.read_done {
+            return Err(SendError::Closing(item));
+        }
+
+        if out.queue.len() >= out.limit {
+            match self.policy {
+                OverflowPolicy::Block => return Err(SendError::Full(item)),
+                OverflowPolicy::Disconnect => {
+                    self.remove(token);
+                    return Err(SendError::Disconnected(item));
+                }
+                OverflowPolicy::DropOldest | OverflowPolicy::DropNewest => {}
+            }
+
+            // an item is dropped, so report it
+            self.overflows.push_back((token, self.policy));
+            // make sure we get polled to report it
+            self.streams.ready_to_run_queue.waker.wake();
+
+            if self.policy == OverflowPolicy::DropNewest || out.queue.pop_front().is_none() {
+                // if the limit is zero, the new item is also the oldest
+                return Ok(());
+            }
+        }
+
+        out.queue.push_back(item);
+        if !out.pending {
+            out.pending = true;
+            self.pending.push(token);
+            // make sure we get polled to send it
+            self.streams.ready_to_run_queue.waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Feeds queued items into the sink of the given stream, and flushes it. Once the stream has
+    /// finished and all its output has been sent, the sink is closed instead.
+    ///
+    /// Returns `Ready(Ok(()))` once the stream has no more pending output.
+    fn poll_flush_one(&mut self, token: usize, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>>
+    where
+        S: Sink<T>,
+    {
+        let out = &mut self.outbound[token];
+        let mut sink = Pin::new(&mut self.streams)
+            .get_pin_mut(token)
+            .expect("pending stream is in the set");
+
+        while !out.queue.is_empty() {
+            match sink.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => break,
+            }
+            sink.as_mut()
+                .start_send(out.queue.pop_front().expect("!is_empty"))?;
+            out.unflushed = true;
+            if let Some(w) = out.blocked.take() {
+                w.wake();
+            }
+        }
+
+        if out.unflushed {
+            match sink.as_mut().poll_flush(cx) {
+                Poll::Ready(Ok(())) => out.unflushed = false,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        if !out.queue.is_empty() {
+            Poll::Pending
+        } else if out.read_done {
+            sink.poll_close(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Makes progress on sending for every stream with pending output.
+    ///
+    /// Returns an event if a stream had to be removed as a result.
+    fn poll_flush_pending(&mut self, cx: &mut Context<'_>) -> Option<Event<S, T>>
+    where
+        S: Stream + Sink<T>,
+    {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let token = self.pending[i];
+            let res = match self.poll_flush_one(token, cx) {
+                Poll::Pending => {
+                    i += 1;
+                    continue;
+                }
+                Poll::Ready(res) => res,
+            };
+
+            self.pending.swap_remove(i);
+            self.outbound[token].pending = false;
+            match res {
+                Ok(()) if self.outbound[token].read_done => {
+                    self.remove(token);
+                    return Some((DuplexYield::Closed, token));
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    self.remove(token);
+                    return Some((DuplexYield::SendError(e), token));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<S, T> Stream for DuplexUnordered<S, T>
+where
+    S: Stream + Sink<T>,
+{
+    type Item = Event<S, T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some((token, policy)) = this.overflows.pop_front() {
+            return Poll::Ready(Some((DuplexYield::Overflow(policy), token)));
+        }
+
+        // send what we can before reading more
+        if let Some(event) = this.poll_flush_pending(cx) {
+            return Poll::Ready(Some(event));
+        }
+
+        loop {
+            match Pin::new(&mut this.streams).poll_next(cx) {
+                Poll::Ready(Some((StreamYield::Item(item), token))) => {
+                    return Poll::Ready(Some((DuplexYield::Item(item), token)));
+                }
+                Poll::Ready(Some((StreamYield::Finished(f), token))) => {
+                    // we may still have output to send, and the sink has to be closed
+                    f.keep();
+                    this.outbound[token].read_done = true;
+                    if this.outbound[token].pending {
+                        continue;
+                    }
+                    match this.poll_flush_one(token, cx) {
+                        Poll::Ready(Ok(())) => {
+                            this.remove(token);
+                            return Poll::Ready(Some((DuplexYield::Closed, token)));
+                        }
+                        Poll::Ready(Err(e)) => {
+                            this.remove(token);
+                            return Poll::Ready(Some((DuplexYield::SendError(e), token)));
+                        }
+                        Poll::Pending => {
+                            this.outbound[token].pending = true;
+                            this.pending.push(token);
+                        }
+                    }
+                }
+                Poll::Ready(Some((StreamYield::Added(_), _))) => {
+                    unreachable!("no source is attached")
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S, T> Unpin for DuplexUnordered<S, T> {}
+
+impl<S, T> Debug for DuplexUnordered<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DuplexUnordered {{ ... }}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::FutureExt;
+    use futures_util::stream::{self, StreamExt};
+    use std::sync::{Arc, Mutex};
+
+    /// What the other end of a [`Conn`] has seen.
+    #[derive(Default)]
+    struct Peer {
+        sent: Vec<u32>,
+        closed: bool,
+    }
+
+    /// Reads the given items, and records what is sent once it has been flushed.
+    struct Conn {
+        rx: stream::Iter<std::vec::IntoIter<u32>>,
+        buf: Vec<u32>,
+        peer: Arc<Mutex<Peer>>,
+    }
+
+    impl Conn {
+        fn new(rx: Vec<u32>) -> (Self, Arc<Mutex<Peer>>) {
+            let peer = Arc::new(Mutex::new(Peer::default()));
+            let c = Conn {
+                rx: stream::iter(rx),
+                buf: Vec::new(),
+                peer: Arc::clone(&peer),
+            };
+            (c, peer)
+        }
+    }
+
+    impl Stream for Conn {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u32>> {
+            self.rx.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<u32> for Conn {
+        type Error = ();
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            if self.buf.len() < 2 {
+                Poll::Ready(Ok(()))
+            } else {
+                // ready again once flushed
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u32) -> Result<(), ()> {
+            if item == 0 {
+                return Err(());
+            }
+            self.buf.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            let buf = std::mem::take(&mut self.buf);
+            self.peer.lock().unwrap().sent.extend(buf);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            futures_core::ready!(self.as_mut().poll_flush(cx))?;
+            self.peer.lock().unwrap().closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn echo() {
+        let mut s = DuplexUnordered::new(4);
+        let (a, a_peer) = Conn::new(vec![1, 2, 3]);
+        let a = s.push(a);
+        let (b, b_peer) = Conn::new(vec![]);
+        let b = s.push(b);
+
+        assert!(s.send_to(b, 7).is_ok());
+        assert_eq!(s.queued(b), Some(1));
+        assert_eq!(s.send_to(42, 7), Err(SendError::Vacant(7)));
+
+        let mut closed = Vec::new();
+        while let Some(Some((event, token))) = s.next().now_or_never() {
+            match event {
+                DuplexYield::Item(i) => s.send_to(token, i * 10).unwrap(),
+                DuplexYield::Closed => closed.push(token),
+                DuplexYield::SendError(()) | DuplexYield::Overflow(_) => unreachable!(),
+            }
+        }
+
+        // both connections are only removed once their output has been sent and they are closed
+        closed.sort_unstable();
+        assert_eq!(closed, vec![a, b]);
+        assert!(s.is_empty());
+        for (peer, sent) in [(a_peer, vec![10, 20, 30]), (b_peer, vec![7])] {
+            let peer = peer.lock().unwrap();
+            assert_eq!(peer.sent, sent);
+            assert!(peer.closed);
+        }
+    }
+
+    #[test]
+    fn full_and_errors() {
+        let mut s = DuplexUnordered::new(2);
+        let (a, _) = Conn::new(vec![1]);
+        let a = s.push(a);
+
+        assert!(s.send_to(a, 1).is_ok());
+        assert!(s.send_to(a, 0).is_ok());
+        assert_eq!(s.send_to(a, 3), Err(SendError::Full(3)));
+
+        // nothing was dropped, so there is no overflow to report
+        assert!(matches!(
+            s.next().now_or_never(),
+            Some(Some((DuplexYield::SendError(()), t))) if t == a
+        ));
+        assert!(s.is_empty());
+        assert_eq!(s.send_to(a, 3).map_err(SendError::into_inner), Err(3));
+    }
+
+    #[test]
+    fn overflow() {
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut s = DuplexUnordered::new(2);
+        let (a, a_peer) = Conn::new(vec![]);
+        let a = s.push(a);
+        let (b, _) = Conn::new(vec![]);
+        let b = s.push(b);
+        assert!(s.set_limit(b, 1));
+        assert_eq!(s.limit(b), Some(1));
+        assert_eq!(s.poll_send_ready(b, &mut cx), Poll::Ready(true));
+
+        // block
+        assert!(s.send_to(b, 1).is_ok());
+        assert_eq!(s.poll_send_ready(b, &mut cx), Poll::Pending);
+        assert_eq!(s.send_to(b, 2), Err(SendError::Full(2)));
+
+        // drop oldest
+        s.set_overflow_policy(OverflowPolicy::DropOldest);
+        for i in 1..=3 {
+            assert!(s.send_to(a, i).is_ok());
+        }
+
+        // drop newest
+        s.set_overflow_policy(OverflowPolicy::DropNewest);
+        assert!(s.send_to(a, 4).is_ok());
+        assert_eq!(s.queued(a), Some(2));
+
+        // disconnect
+        s.set_overflow_policy(OverflowPolicy::Disconnect);
+        assert_eq!(s.send_to(b, 2), Err(SendError::Disconnected(2)));
+        assert_eq!(s.poll_send_ready(b, &mut cx), Poll::Ready(false));
+
+        // b's overflows went with it
+        let mut events = Vec::new();
+        while let Some(Some((event, token))) = s.next().now_or_never() {
+            if let DuplexYield::Overflow(p) = event {
+                events.push((token, p));
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                (a, OverflowPolicy::DropOldest),
+                (a, OverflowPolicy::DropNewest),
+            ]
+        );
+        assert_eq!(a_peer.lock().unwrap().sent, vec![2, 3]);
+    }
+
+    #[test]
+    fn overflow_reported_once_per_drop() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let mut s = DuplexUnordered::new(1);
+            s.set_overflow_policy(policy);
+            let (a, a_peer) = Conn::new(vec![]);
+            let a = s.push(a);
+            for i in 1..=4 {
+                assert!(s.send_to(a, i).is_ok());
+            }
+
+            let mut overflows = Vec::new();
+            while let Some(Some((event, token))) = s.next().now_or_never() {
+                if let DuplexYield::Overflow(p) = event {
+                    overflows.push((token, p));
+                }
+            }
+            assert_eq!(overflows, vec![(a, policy); 3]);
+            let kept = if policy == OverflowPolicy::DropOldest {
+                4
+            } else {
+                1
+            };
+            assert_eq!(a_peer.lock().unwrap().sent, vec![kept]);
+        }
+
+        // a disconnect is reported to the sender instead, and only once
+        let mut s = DuplexUnordered::new(1);
+        s.set_overflow_policy(OverflowPolicy::Disconnect);
+        let (a, _) = Conn::new(vec![1]);
+        let a = s.push(a);
+        assert!(s.send_to(a, 1).is_ok());
+        assert_eq!(s.send_to(a, 2), Err(SendError::Disconnected(2)));
+        assert_eq!(s.send_to(a, 3), Err(SendError::Vacant(3)));
+        assert!(matches!(s.next().now_or_never(), Some(None)));
+    }
+
+    #[test]
+    fn send_while_closing() {
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut s = DuplexUnordered::new(4);
+        let (a, a_peer) = Conn::new(vec![]);
+        let a = s.push(a);
+        for i in 1..=3 {
+            assert!(s.send_to(a, i).is_ok());
+        }
+
+        // the stream finishes while there is still output queued for it
+        assert!(s.next().now_or_never().is_none());
+        assert_eq!(s.send_to(a, 4), Err(SendError::Closing(4)));
+        assert_eq!(s.poll_send_ready(a, &mut cx), Poll::Ready(false));
+
+        assert!(matches!(
+            s.next().now_or_never(),
+            Some(Some((DuplexYield::Closed, t))) if t == a
+        ));
+        let peer = a_peer.lock().unwrap();
+        assert_eq!(peer.sent, vec![1, 2, 3]);
+        assert!(peer.closed);
+    }
+
+    #[test]
+    fn overflow_of_removed_stream() {
+        let mut s = DuplexUnordered::new(0);
+        s.set_overflow_policy(OverflowPolicy::DropNewest);
+        let (a, _) = Conn::new(vec![1]);
+        let a = s.push(a);
+        assert_eq!(s.send_to(a, 1), Ok(()));
+
+        // the overflow must not be reported for the stream that reuses the token
+        assert!(s.remove(a));
+        let (b, _) = Conn::new(vec![1]);
+        assert_eq!(s.push(b), a);
+        assert!(matches!(
+            s.next().now_or_never(),
+            Some(Some((DuplexYield::Item(1), t))) if t == a
+        ));
+    }
+}
@@ -0,0 +1,652 @@
+//! A variant of `StreamUnordered` for streams of different types.
+//!
+//! The usual way to put streams of different types into one `StreamUnordered` is to box them as
+//! `Pin<Box<dyn Stream>>`, which costs a separate allocation on top of the one for the `Task`. A
+//! `DynStreamUnordered` instead allocates each task together with the concrete stream it holds,
+//! and keeps a trait object pointer to that stream in the task.
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+use futures_core::future::Future;
+use futures_core::stream::{FusedStream, Stream};
+use futures_core::task::{Context, Poll};
+use futures_util::stream::{self, Once};
+use futures_util::task::AtomicWaker;
+
+use super::ready_to_run_queue::{Dequeue, ReadyToRunQueue};
+use super::{FinishedStream, StreamSet, StreamYield, TERMINATED_SENTINEL_LENGTH};
+
+mod task;
+use self::task::{Task, TaskRef};
+
+/// Stream trait objects that a [`DynStreamUnordered`] can store streams of type `St` as.
+///
+/// Turning a concrete stream into a trait object is an unsizing coercion, which cannot be
+/// expressed as a generic bound. This trait provides that coercion instead. It is implemented for
+/// `dyn Stream<Item = T> + 'a` for all streams that live for `'a`, and for
+/// `dyn Stream<Item = T> + Send + 'a` for all such streams that are also `Send`. Note that the
+/// default trait objects of [`DynStreamUnordered`] and [`LocalDynStreamUnordered`] are `'static`;
+/// name the lifetime explicitly, as in `DynStreamUnordered<T, dyn Stream<Item = T> + 'a>`, to
+/// store streams that borrow from their environment.
+///
+/// # Safety
+///
+/// `coerce` must return a pointer to the same stream that it was given.
+pub unsafe trait DynStream<St>: Stream {
+    /// Turn a pointer to a concrete stream into a trait object pointer.
+    fn coerce(stream: *mut St) -> *mut Self;
+}
+
+unsafe impl<'a, T, St> DynStream<St> for dyn Stream<Item = T> + 'a
+where
+    St: Stream<Item = T> + 'a,
+{
+    fn coerce(stream: *mut St) -> *mut Self {
+        stream
+    }
+}
+
+unsafe impl<'a, T, St> DynStream<St> for dyn Stream<Item = T> + Send + 'a
+where
+    St: Stream<Item = T> + Send + 'a,
+{
+    fn coerce(stream: *mut St) -> *mut Self {
+        stream
+    }
+}
+
+/// A set of streams of different types that all yield `T`, which may yield items in any order.
+///
+/// This works just like a [`StreamUnordered`](crate::StreamUnordered) of
+/// `Pin<Box<dyn Stream<Item = T> + Send>>`, except that each stream is stored in the same
+/// allocation as the rest of the state the set keeps for it, so that
+/// [`push`](DynStreamUnordered::push) only allocates once.
+///
+/// By default, the streams must be `Send`, which makes the set `Send` as well. Use
+/// [`LocalDynStreamUnordered`] for streams that are not `Send`.
+///
+/// Since the streams need not be of the same type, one-shot futures can be mixed in with them
+/// using [`push_future`](DynStreamUnordered::push_future).
+#[must_use = "streams do nothing unless polled"]
+pub struct DynStreamUnordered<T, D: ?Sized = dyn Stream<Item = T> + Send>
+where
+    D: Stream<Item = T>,
+{
+    ready_to_run_queue: Arc<ReadyToRunQueue<Task<D>>>,
+    len: usize,
+    head_all: *const Task<D>,
+    by_id: slab::Slab<*const Task<D>>,
+}
+
+/// A [`DynStreamUnordered`] for streams that are not `Send`.
+pub type LocalDynStreamUnordered<T> = DynStreamUnordered<T, dyn Stream<Item = T>>;
+
+unsafe impl<T, D: ?Sized + Stream<Item = T> + Send> Send for DynStreamUnordered<T, D> {}
+unsafe impl<T, D: ?Sized + Stream<Item = T> + Sync> Sync for DynStreamUnordered<T, D> {}
+impl<T, D: ?Sized + Stream<Item = T>> Unpin for DynStreamUnordered<T, D> {}
+
+/// A handle to an vacant stream slot in a `DynStreamUnordered`.
+///
+/// `DynStreamEntry` allows constructing streams that hold the token that they will be assigned.
+pub struct DynStreamEntry<'a, T, D: ?Sized + Stream<Item = T>> {
+    token: usize,
+    backref: &'a mut DynStreamUnordered<T, D>,
+}
+
+impl<'a, T, D: ?Sized + Stream<Item = T>> DynStreamEntry<'a, T, D> {
+    /// Insert a stream in the slot.
+    pub fn insert<St>(self, stream: St)
+    where
+        D: DynStream<St>,
+    {
+        self.backref.insert(self.token, stream);
+    }
+
+    /// Return the token associated with this slot.
+    ///
+    /// A stream stored in this slot will be associated with this token.
+    pub fn token(&self) -> usize {
+        self.token
+    }
+}
+
+impl<'a, T, D: ?Sized + Stream<Item = T>> Debug for DynStreamEntry<'a, T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynStreamEntry")
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> DynStreamUnordered<T, D> {
+    /// Constructs a new, empty [`DynStreamUnordered`].
+    ///
+    /// The returned [`DynStreamUnordered`] does not contain any streams.
+    /// In this state, [`DynStreamUnordered::poll_next`](Stream::poll_next) will
+    /// return [`Poll::Ready(None)`](Poll::Ready).
+    pub fn new() -> Self {
+        let mut slab = slab::Slab::new();
+        let stub = Task::stub();
+        let stub_ptr = &*stub as *const Task<D>;
+
+        // As in `StreamUnordered`, token 0 belongs to the stub.
+        let _ = slab.insert(stub_ptr);
+
+        let ready_to_run_queue = Arc::new(ReadyToRunQueue {
+            waker: AtomicWaker::new(),
+            head: AtomicPtr::new(stub_ptr as *mut _),
+            tail: UnsafeCell::new(stub_ptr),
+            stub,
+            recycling: AtomicBool::new(false),
+        });
+
+        DynStreamUnordered {
+            len: 0,
+            head_all: ptr::null(),
+            ready_to_run_queue,
+            by_id: slab,
+        }
+    }
+
+    /// Returns the number of streams contained in the set.
+    ///
+    /// This represents the total number of in-flight streams.
+    pub fn len(&self) -> usize {
+        if self.len == TERMINATED_SENTINEL_LENGTH {
+            0
+        } else {
+            self.len
+        }
+    }
+
+    /// Returns `true` if the set contains no streams.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 || self.len == TERMINATED_SENTINEL_LENGTH
+    }
+
+    /// Returns a handle to a vacant stream entry allowing for further manipulation.
+    ///
+    /// See [`StreamUnordered::stream_entry`](crate::StreamUnordered::stream_entry).
+    pub fn stream_entry(&mut self) -> DynStreamEntry<'_, T, D> {
+        // The task can't be allocated until we know the type of the stream, so for now we just
+        // look up the token it will get. Nothing else can take that token in the meantime, since
+        // the entry borrows the set mutably.
+        DynStreamEntry {
+            token: self.by_id.vacant_key(),
+            backref: self,
+        }
+    }
+
+    /// Push a stream into the set.
+    ///
+    /// See [`StreamUnordered::push`](crate::StreamUnordered::push).
+    pub fn push<St>(&mut self, stream: St) -> usize
+    where
+        D: DynStream<St>,
+    {
+        let s = self.stream_entry();
+        let token = s.token();
+        s.insert(stream);
+        token
+    }
+
+    /// Push a future into the set.
+    ///
+    /// The future shares its token and the ready queue with the streams in the set. Once it
+    /// resolves, the set yields its output as a [`StreamYield::Item`], followed by
+    /// [`StreamYield::Finished`] for the same token. Like for streams, the future is only
+    /// dropped once it is [`remove`](DynStreamUnordered::remove)d.
+    ///
+    /// This is a shorthand for pushing [`stream::once(future)`](futures_util::stream::once).
+    pub fn push_future<F>(&mut self, future: F) -> usize
+    where
+        F: Future<Output = T>,
+        D: DynStream<Once<F>>,
+    {
+        self.push(stream::once(future))
+    }
+
+    fn insert<St>(&mut self, token: usize, stream: St)
+    where
+        D: DynStream<St>,
+    {
+        let task = Task::allocate(
+            stream,
+            D::coerce,
+            Arc::downgrade(&self.ready_to_run_queue),
+            token,
+        );
+        let key = self.by_id.insert(&*task as *const _);
+        debug_assert_eq!(key, token);
+
+        // If we've previously marked ourselves as terminated we need to reset
+        // len to 0 to track it correctly
+        if self.len == TERMINATED_SENTINEL_LENGTH {
+            self.len = 0;
+        }
+
+        // Just like for `StreamUnordered`, the linked list takes ownership of
+        // the task's reference count, and we unconditionally enqueue the task
+        // so that its stream gets polled for the first time.
+        let ptr = self.link(task);
+        self.ready_to_run_queue.enqueue(ptr);
+    }
+
+    /// Remove a stream from the set.
+    ///
+    /// The stream will be dropped and will no longer yield stream events.
+    pub fn remove(mut self: Pin<&mut Self>, token: usize) -> bool {
+        if token == 0 {
+            return false;
+        }
+
+        let task = if let Some(task) = self.by_id.get(token) {
+            *task
+        } else {
+            return false;
+        };
+
+        // we know that by_id only references valid tasks
+        let task = unsafe { self.unlink(task) };
+        self.release_task(task);
+        true
+    }
+
+    /// Returns `true` if the stream with the given token has yielded `None`.
+    pub fn is_finished(&self, token: usize) -> Option<bool> {
+        if token == 0 {
+            return None;
+        }
+
+        // we know that by_id only references valid tasks
+        Some(unsafe { *(**self.by_id.get(token)?).is_done.get() })
+    }
+
+    /// Returns a reference to the stream with the given token
+    pub fn get(&self, token: usize) -> Option<&D> {
+        // don't allow access to the 0th task, since it's not a stream
+        if token == 0 {
+            return None;
+        }
+
+        // we know that by_id only references valid tasks
+        Some(unsafe { &*(*(**self.by_id.get(token)?).stream.get())?.as_ptr() })
+    }
+
+    /// Returns a pinned reference that allows modifying the stream with the given token.
+    pub fn get_pin_mut(mut self: Pin<&mut Self>, token: usize) -> Option<Pin<&mut D>> {
+        // don't allow access to the 0th task, since it's not a stream
+        if token == 0 {
+            return None;
+        }
+
+        // the stream never moves, since it lives inside the task
+        Some(unsafe {
+            Pin::new_unchecked(&mut *(*(**self.by_id.get_mut(token)?).stream.get())?.as_ptr())
+        })
+    }
+
+    /// Releases the task. It destroys the stream inside and either drops
+    /// the task or transfers ownership to the ready to run queue.
+    /// The task this method is called on must have been unlinked before.
+    fn release_task(&mut self, task: TaskRef<D>) {
+        self.by_id.remove(task.id);
+
+        // `release_task` must only be called on unlinked tasks
+        unsafe {
+            debug_assert!((*task.next_all.get()).is_null());
+            debug_assert!((*task.prev_all.get()).is_null());
+        }
+
+        // See `StreamUnordered::release_task`. A parked task is not actually in
+        // the ready to run queue, even though its queued flag is set.
+        let prev = task.queued.swap(true, SeqCst) && !unsafe { *task.is_parked.get() };
+
+        // Drop the stream in place, since it lives inside the task. This is
+        // safe because we're doing so on the thread that owns the set.
+        unsafe { task.drop_stream() };
+
+        if prev {
+            let _ = task.into_raw();
+        }
+    }
+
+    /// Insert a new task into the internal linked list.
+    fn link(&mut self, task: TaskRef<D>) -> *const Task<D> {
+        let ptr = task.into_raw();
+        unsafe {
+            *(*ptr).next_all.get() = self.head_all;
+            if !self.head_all.is_null() {
+                *(*self.head_all).prev_all.get() = ptr;
+            }
+        }
+
+        self.head_all = ptr;
+        self.len += 1;
+        ptr
+    }
+
+    /// Remove the task from the linked list tracking all tasks currently
+    /// managed by `DynStreamUnordered`.
+    /// This method is unsafe because it has be guaranteed that `task` is a
+    /// valid pointer.
+    unsafe fn unlink(&mut self, task: *const Task<D>) -> TaskRef<D> {
+        let task = TaskRef::from_raw(task);
+
+        let next = *task.next_all.get();
+        let prev = *task.prev_all.get();
+        *task.next_all.get() = ptr::null();
+        *task.prev_all.get() = ptr::null();
+
+        if !next.is_null() {
+            *(*next).prev_all.get() = prev;
+        }
+
+        if !prev.is_null() {
+            *(*prev).next_all.get() = next;
+        } else {
+            self.head_all = next;
+        }
+        self.len -= 1;
+        task
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> Default for DynStreamUnordered<T, D> {
+    fn default() -> Self {
+        DynStreamUnordered::new()
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> StreamSet for DynStreamUnordered<T, D> {
+    type Stream = D;
+
+    fn remove_stream(self: Pin<&mut Self>, token: usize) -> bool {
+        self.remove(token)
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> Stream for DynStreamUnordered<T, D> {
+    type Item = (StreamYield<D>, usize);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Ensure `parent` is correctly set.
+        self.ready_to_run_queue.waker.register(cx.waker());
+
+        loop {
+            // Safety: &mut self guarantees the mutual exclusion `dequeue`
+            // expects
+            let task = match unsafe { self.ready_to_run_queue.dequeue() } {
+                Dequeue::Empty => {
+                    if self.is_empty() {
+                        // We can only consider ourselves terminated once we
+                        // have yielded a `None`
+                        self.len = TERMINATED_SENTINEL_LENGTH;
+                        return Poll::Ready(None);
+                    } else {
+                        return Poll::Pending;
+                    }
+                }
+                Dequeue::Inconsistent => {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Dequeue::Data(task) => task,
+            };
+
+            debug_assert!(task != self.ready_to_run_queue.stub());
+
+            let stream = match unsafe { *(*task).stream.get() } {
+                Some(stream) => stream,
+
+                // The task was released while it was in the ready to run
+                // queue, so we now own its reference count. See the same case
+                // in `StreamUnordered::poll_next`.
+                None => {
+                    let task = unsafe { TaskRef::from_raw(task) };
+                    unsafe {
+                        debug_assert!((*task.next_all.get()).is_null());
+                        debug_assert!((*task.prev_all.get()).is_null());
+                    }
+                    continue;
+                }
+            };
+
+            // Safety: we only ever access is_done and is_parked on the thread that owns the set.
+            if unsafe { *(*task).is_done.get() } {
+                // This stream has already been polled to completion, but the user has not
+                // removed it yet. We park the task with its queued flag set, which absorbs any
+                // further wake-ups.
+                unsafe { *(*task).is_parked.get() = true };
+                continue;
+            }
+
+            // Safety: `task` is a valid pointer
+            let task = unsafe { self.unlink(task) };
+
+            // Unset queued flag: This must be done before polling to ensure
+            // that the stream's task gets rescheduled if it sends a wake-up
+            // notification **during** the call to `poll`.
+            let prev = task.queued.swap(false, SeqCst);
+            assert!(prev);
+
+            // See the `Bomb` in `StreamUnordered::poll_next`.
+            struct Bomb<'a, T, D: ?Sized + Stream<Item = T>> {
+                queue: &'a mut DynStreamUnordered<T, D>,
+                task: Option<TaskRef<D>>,
+                _item: PhantomData<T>,
+            }
+
+            impl<T, D: ?Sized + Stream<Item = T>> Drop for Bomb<'_, T, D> {
+                fn drop(&mut self) {
+                    if let Some(task) = self.task.take() {
+                        self.queue.release_task(task);
+                    }
+                }
+            }
+
+            let id = task.id;
+            let mut bomb = Bomb {
+                task: Some(task),
+                queue: &mut *self,
+                _item: PhantomData,
+            };
+
+            let res = {
+                let waker = bomb.task.as_ref().unwrap().waker_ref();
+                let mut cx = Context::from_waker(&waker);
+
+                // Safety: the stream lives inside the task, and will not move
+                let stream = unsafe { Pin::new_unchecked(&mut *stream.as_ptr()) };
+
+                stream.poll_next(&mut cx)
+            };
+
+            match res {
+                Poll::Pending => {
+                    let task = bomb.task.take().unwrap();
+                    bomb.queue.link(task);
+                    continue;
+                }
+                Poll::Ready(None) => {
+                    // The stream has completed -- let the user know, but leave
+                    // it to them to remove it.
+                    let task = bomb.task.take().unwrap();
+                    unsafe {
+                        *task.is_done.get() = true;
+                    }
+                    bomb.queue.link(task);
+
+                    return Poll::Ready(Some((
+                        StreamYield::Finished(FinishedStream { token: id }),
+                        id,
+                    )));
+                }
+                Poll::Ready(Some(output)) => {
+                    // We're not done with the stream just because it yielded something
+                    // We're going to need to poll it again!
+                    bomb.task.as_ref().unwrap().waker_ref().wake_by_ref();
+
+                    // And also return it to the task queue
+                    let task = bomb.task.take().unwrap();
+                    bomb.queue.link(task);
+
+                    return Poll::Ready(Some((StreamYield::Item(output), id)));
+                }
+            }
+        }
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> FusedStream for DynStreamUnordered<T, D> {
+    fn is_terminated(&self) -> bool {
+        self.len == TERMINATED_SENTINEL_LENGTH
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> Debug for DynStreamUnordered<T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DynStreamUnordered {{ ... }}")
+    }
+}
+
+impl<T, D: ?Sized + Stream<Item = T>> Drop for DynStreamUnordered<T, D> {
+    fn drop(&mut self) {
+        // See `Drop for StreamUnordered`.
+        unsafe {
+            while !self.head_all.is_null() {
+                let head = self.head_all;
+                let task = self.unlink(head);
+                self.release_task(task);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, stream::StreamExt};
+
+    #[test]
+    fn no_starvation() {
+        let mut s = LocalDynStreamUnordered::new();
+        let forever0 = s.push(stream::iter(vec![0].into_iter().cycle()));
+        let forever1 = s.push(stream::repeat(1));
+        let two = s.push(stream::once(async { 2 }));
+        let s = futures::executor::block_on(s.take(100).collect::<Vec<_>>());
+        crate::micro::check_no_starvation(s, forever0, forever1, two);
+    }
+
+    #[test]
+    fn futures_and_streams() {
+        let (tx, rx) = futures::channel::oneshot::channel::<u32>();
+        let mut s: LocalDynStreamUnordered<u32> = DynStreamUnordered::new();
+        let st = s.push(stream::iter(vec![1, 2]));
+        let fut = s.push_future(async move { rx.await.unwrap() });
+        tx.send(3).unwrap();
+
+        let got = futures::executor::block_on(s.take(5).collect::<Vec<_>>());
+        let items: Vec<_> = got
+            .iter()
+            .filter_map(|(y, si)| match y {
+                StreamYield::Item(v) => Some((*v, *si)),
+                StreamYield::Finished(_) | StreamYield::Added(_) => None,
+            })
+            .collect();
+        assert_eq!(items.len(), 3);
+        assert!(items.contains(&(3, fut)));
+        assert_eq!(
+            items
+                .iter()
+                .filter(|&&(_, si)| si == st)
+                .collect::<Vec<_>>(),
+            vec![&(1, st), &(2, st)]
+        );
+
+        // each one announces its end after its last item
+        let end = |token| {
+            got.iter()
+                .rposition(|&(ref y, si)| si == token && matches!(y, StreamYield::Finished(_)))
+        };
+        let last = |token| got.iter().rposition(|&(_, si)| si == token);
+        assert_eq!(end(st), last(st));
+        assert_eq!(end(fut), last(fut));
+    }
+
+    #[test]
+    fn drops_streams() {
+        let (tx, rx) = futures::channel::mpsc::unbounded::<()>();
+        let mut s: DynStreamUnordered<()> = DynStreamUnordered::new();
+        let t = s.push(rx);
+        let _ = s.stream_entry();
+        let u = s.push(stream::empty());
+        assert_eq!(s.len(), 2);
+        assert!(!tx.is_closed());
+        assert!(Pin::new(&mut s).remove(t));
+        assert!(tx.is_closed());
+        assert!(s.get(u).is_some());
+        assert!(s.get(t).is_none());
+    }
+
+    #[test]
+    fn borrowed_streams() {
+        let items = vec![1, 2, 3];
+        let mut s: DynStreamUnordered<&i32, dyn Stream<Item = &i32> + '_> =
+            DynStreamUnordered::new();
+        s.push(stream::iter(&items));
+        let got: Vec<_> = futures::executor::block_on(s.take(4).collect::<Vec<_>>())
+            .into_iter()
+            .filter_map(|(y, _)| match y {
+                StreamYield::Item(&x) => Some(x),
+                StreamYield::Finished(_) | StreamYield::Added(_) => None,
+            })
+            .collect();
+        assert_eq!(got, items);
+    }
+
+    #[test]
+    fn remove_after_spurious_wakeup() {
+        use futures_util::future::FutureExt;
+
+        let mut s = LocalDynStreamUnordered::new();
+        let waker = Arc::new(AtomicWaker::new());
+        let w = Arc::clone(&waker);
+        let a = s.push(stream::poll_fn(move |cx| -> Poll<Option<()>> {
+            w.register(cx.waker());
+            Poll::Ready(None)
+        }));
+        match s.next().now_or_never() {
+            Some(Some((StreamYield::Finished(f), t))) if t == a => f.keep(),
+            y => panic!("{:?}", y),
+        }
+
+        // the finished stream is woken, and then removed before it is polled again
+        waker.wake();
+        assert!(s.next().now_or_never().is_none());
+        assert!(Pin::new(&mut s).remove(a));
+        assert_eq!(Arc::weak_count(&s.ready_to_run_queue), 0);
+    }
+
+    #[test]
+    // the entry has nothing to clean up on drop, which is exactly what we're checking
+    #[allow(clippy::forget_non_drop)]
+    fn forgotten_entry() {
+        let mut s: DynStreamUnordered<()> = DynStreamUnordered::new();
+        let entry = s.stream_entry();
+        let t = entry.token();
+        core::mem::forget(entry);
+        assert!(s.get(t).is_none());
+        assert!(Pin::new(&mut s).get_pin_mut(t).is_none());
+        assert_eq!(s.is_finished(t), None);
+        assert!(!Pin::new(&mut s).remove(t));
+        assert_eq!(s.len(), 0);
+        assert_eq!(s.push(stream::empty()), t);
+    }
+}
@@ -0,0 +1,254 @@
+use alloc::sync::{Arc, Weak};
+use core::cell::UnsafeCell;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+use core::task::{RawWaker, RawWakerVTable, Waker};
+
+use crate::abort::abort;
+use crate::ready_to_run_queue::{Node, ReadyToRunQueue};
+
+/// The part of a task that does not depend on the concrete type of its stream.
+///
+/// Every task is allocated as an `Arc<Inline<D, St>>`, which holds the stream inline right after
+/// this header. Everywhere else we only deal in pointers to the header, and go through `refs` to
+/// manipulate the reference count of the full allocation.
+pub(super) struct Task<D: ?Sized> {
+    // The stream, which lives in the same allocation as this header.
+    // `None` once the stream has been dropped.
+    pub(super) stream: UnsafeCell<Option<NonNull<D>>>,
+
+    // Indicator that the stream has already completed.
+    pub(super) is_done: UnsafeCell<bool>,
+
+    // Indicator that the task was taken off the ready to run queue without
+    // being polled (since it was finished), and so is no longer in that
+    // queue even though its `queued` flag is still set.
+    pub(super) is_parked: UnsafeCell<bool>,
+
+    // Next pointer for linked list tracking all active tasks
+    pub(super) next_all: UnsafeCell<*const Task<D>>,
+
+    // Previous task in linked list tracking all active tasks
+    pub(super) prev_all: UnsafeCell<*const Task<D>>,
+
+    // Next pointer in ready to run queue
+    pub(super) next_ready_to_run: AtomicPtr<Task<D>>,
+
+    // Queue that we'll be enqueued to when woken
+    pub(super) ready_to_run_queue: Weak<ReadyToRunQueue<Task<D>>>,
+
+    // Whether or not this task is currently in the ready to run queue
+    pub(super) queued: AtomicBool,
+
+    // A unique identifier for this stream
+    pub(super) id: usize,
+
+    // Reference counting for the allocation this task lives in
+    refs: RefCounts<D>,
+}
+
+// See the same impls for `crate::task::Task`.
+unsafe impl<D: ?Sized> Send for Task<D> {}
+unsafe impl<D: ?Sized> Sync for Task<D> {}
+
+struct RefCounts<D: ?Sized> {
+    increment: unsafe fn(*const Task<D>),
+    decrement: unsafe fn(*const Task<D>),
+}
+
+#[repr(C)]
+struct Inline<D: ?Sized, St> {
+    task: Task<D>,
+    stream: UnsafeCell<MaybeUninit<St>>,
+}
+
+impl<D: ?Sized, St> Inline<D, St> {
+    const REFS: RefCounts<D> = RefCounts {
+        increment: Self::increment,
+        decrement: Self::decrement,
+    };
+
+    unsafe fn increment(task: *const Task<D>) {
+        // `Inline` is `repr(C)`, so a pointer to its first field is a pointer to it.
+        Arc::increment_strong_count(task as *const Self);
+    }
+
+    unsafe fn decrement(task: *const Task<D>) {
+        drop(Arc::from_raw(task as *const Self));
+    }
+}
+
+impl<D: ?Sized> Task<D> {
+    fn header(
+        ready_to_run_queue: Weak<ReadyToRunQueue<Task<D>>>,
+        id: usize,
+        refs: RefCounts<D>,
+    ) -> Self {
+        Task {
+            stream: UnsafeCell::new(None),
+            is_done: UnsafeCell::new(false),
+            is_parked: UnsafeCell::new(false),
+            next_all: UnsafeCell::new(ptr::null()),
+            prev_all: UnsafeCell::new(ptr::null()),
+            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(true),
+            ready_to_run_queue,
+            id,
+            refs,
+        }
+    }
+
+    /// Allocate a task that holds `stream` inline.
+    ///
+    /// `coerce` must turn a pointer to the stream into a trait object pointer to the same stream.
+    pub(super) fn allocate<St>(
+        stream: St,
+        coerce: fn(*mut St) -> *mut D,
+        ready_to_run_queue: Weak<ReadyToRunQueue<Task<D>>>,
+        id: usize,
+    ) -> TaskRef<D> {
+        let inline = Arc::new(Inline {
+            task: Task::header(ready_to_run_queue, id, Inline::<D, St>::REFS),
+            stream: UnsafeCell::new(MaybeUninit::new(stream)),
+        });
+
+        // The stream never moves again after this point, so it's fine for the header to point
+        // to it. It is dropped in place when the task is released.
+        let stream = coerce(inline.stream.get() as *mut St);
+        unsafe { *inline.task.stream.get() = Some(NonNull::new_unchecked(stream)) };
+
+        TaskRef(unsafe { NonNull::new_unchecked(Arc::into_raw(inline) as *mut Task<D>) })
+    }
+
+    /// Allocate the stub task for the ready to run queue, which never holds a stream.
+    pub(super) fn stub() -> Arc<Task<D>> {
+        unsafe fn never<D: ?Sized>(_: *const Task<D>) {
+            abort("stub task reference count touched");
+        }
+
+        Arc::new(Task::header(
+            Weak::new(),
+            0,
+            RefCounts {
+                increment: never::<D>,
+                decrement: never::<D>,
+            },
+        ))
+    }
+
+    /// Drop the stream in place, if it is still there.
+    ///
+    /// This must only be called from the thread that owns the `DynStreamUnordered`.
+    pub(super) unsafe fn drop_stream(&self) {
+        if let Some(stream) = (*self.stream.get()).take() {
+            ptr::drop_in_place(stream.as_ptr());
+        }
+    }
+
+    fn wake_by_ref(&self) {
+        let inner = match self.ready_to_run_queue.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        // See `crate::task::Task::schedule`.
+        let prev = self.queued.swap(true, SeqCst);
+        if !prev {
+            inner.enqueue(self);
+            inner.waker.wake();
+        }
+    }
+}
+
+impl<D: ?Sized> Node for Task<D> {
+    fn next_ready_to_run(&self) -> &AtomicPtr<Self> {
+        &self.next_ready_to_run
+    }
+
+    fn queued(&self) -> &AtomicBool {
+        &self.queued
+    }
+
+    unsafe fn release(task: *const Self) {
+        drop(TaskRef::from_raw(task));
+    }
+}
+
+impl<D: ?Sized> Drop for Task<D> {
+    fn drop(&mut self) {
+        // See `Drop for crate::task::Task`.
+        unsafe {
+            if (*self.stream.get()).is_some() {
+                abort("stream still here when dropping");
+            }
+        }
+    }
+}
+
+/// An owned reference count on a task, like an `Arc<Task<D>>`.
+pub(super) struct TaskRef<D: ?Sized>(NonNull<Task<D>>);
+
+impl<D: ?Sized> TaskRef<D> {
+    pub(super) fn into_raw(self) -> *const Task<D> {
+        ManuallyDrop::new(self).0.as_ptr()
+    }
+
+    /// The pointer must have come from `TaskRef::into_raw`.
+    pub(super) unsafe fn from_raw(task: *const Task<D>) -> Self {
+        TaskRef(NonNull::new_unchecked(task as *mut _))
+    }
+
+    /// Returns a waker reference for this task without touching the reference count.
+    pub(super) fn waker_ref(&self) -> ManuallyDrop<Waker> {
+        // Safety: the returned waker does not own a reference count, which is why it is wrapped
+        // in `ManuallyDrop`. Any clones made from it _do_ own a reference count.
+        ManuallyDrop::new(unsafe { Waker::from_raw(Self::raw_waker(self.0.as_ptr())) })
+    }
+
+    fn raw_waker(task: *const Task<D>) -> RawWaker {
+        RawWaker::new(task as *const (), Self::VTABLE)
+    }
+
+    const VTABLE: &'static RawWakerVTable = &RawWakerVTable::new(
+        Self::clone_waker,
+        Self::wake,
+        Self::wake_by_ref_raw,
+        Self::drop_waker,
+    );
+
+    unsafe fn clone_waker(data: *const ()) -> RawWaker {
+        let task = data as *const Task<D>;
+        ((*task).refs.increment)(task);
+        Self::raw_waker(task)
+    }
+
+    unsafe fn wake(data: *const ()) {
+        let task = Self::from_raw(data as *const Task<D>);
+        task.wake_by_ref();
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        (*(data as *const Task<D>)).wake_by_ref();
+    }
+
+    unsafe fn drop_waker(data: *const ()) {
+        drop(Self::from_raw(data as *const Task<D>));
+    }
+}
+
+impl<D: ?Sized> Deref for TaskRef<D> {
+    type Target = Task<D>;
+
+    fn deref(&self) -> &Task<D> {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl<D: ?Sized> Drop for TaskRef<D> {
+    fn drop(&mut self) {
+        unsafe { (self.refs.decrement)(self.0.as_ptr()) }
+    }
+}
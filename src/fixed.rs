@@ -0,0 +1,586 @@
+//! A fixed-capacity variant of `StreamUnordered` that never allocates.
+//!
+//! Rather than giving each stream its own reference-counted `Task`, the streams are stored inline
+//! in an array in the set itself. The state that wakers need to touch (the `queued` flags and the
+//! ready to run queue) lives in a separate [`StaticReadyQueue`] that the user places in a
+//! `static`. Since that queue outlives any waker, the wakers can use a static vtable without any
+//! reference counting.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Debug};
+use core::mem;
+use core::ops::{Index, IndexMut};
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+use core::task::{RawWaker, RawWakerVTable, Waker};
+use futures_core::stream::{FusedStream, Stream};
+use futures_core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+use super::{FinishedStream, StreamSet, StreamYield, TakeStream};
+
+struct Slot {
+    // Next pointer in ready to run queue
+    next_ready_to_run: AtomicPtr<Slot>,
+
+    // Whether or not this slot is currently in the ready to run queue
+    queued: AtomicBool,
+
+    // The queue this slot belongs to.
+    //
+    // This is only set once the `StaticReadyQueue` has been given to a `StaticStreamUnordered`,
+    // since there's no way to refer to a `static` from its own initializer.
+    queue: AtomicPtr<RawQueue>,
+}
+
+impl Slot {
+    const fn new(queued: bool) -> Self {
+        Slot {
+            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(queued),
+            queue: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    const VTABLE: RawWakerVTable =
+        RawWakerVTable::new(Self::clone_waker, Self::wake, Self::wake, Self::drop_waker);
+
+    fn waker(&'static self) -> Waker {
+        // Safety: the vtable functions below uphold the `RawWaker` contract, and the data
+        // pointer is valid forever since it is `'static`.
+        unsafe {
+            Waker::from_raw(RawWaker::new(
+                self as *const Slot as *const (),
+                &Self::VTABLE,
+            ))
+        }
+    }
+
+    unsafe fn clone_waker(data: *const ()) -> RawWaker {
+        RawWaker::new(data, &Self::VTABLE)
+    }
+
+    unsafe fn wake(data: *const ()) {
+        let slot = &*(data as *const Slot);
+
+        // Same as `Task::schedule`: only the waker that flips `queued` gets to enqueue.
+        if !slot.queued.swap(true, SeqCst) {
+            let queue = &*slot.queue.load(Relaxed);
+            queue.enqueue(slot);
+            queue.waker.wake();
+        }
+    }
+
+    unsafe fn drop_waker(_: *const ()) {}
+}
+
+enum Dequeue {
+    Data(*const Slot),
+    Empty,
+    Inconsistent,
+}
+
+struct RawQueue {
+    // The waker of the task using `StaticStreamUnordered`.
+    waker: AtomicWaker,
+
+    // Head/tail of the readiness queue.
+    //
+    // These are null until the queue is first bound, at which point they start out pointing to
+    // `stub`.
+    head: AtomicPtr<Slot>,
+    tail: UnsafeCell<*const Slot>,
+    stub: Slot,
+}
+
+/// The same intrusive MPSC queue that `StreamUnordered` uses, but over fixed slots.
+impl RawQueue {
+    fn enqueue(&self, slot: *const Slot) {
+        unsafe {
+            debug_assert!((*slot).queued.load(Relaxed));
+
+            // This action does not require any coordination
+            (*slot).next_ready_to_run.store(ptr::null_mut(), Relaxed);
+
+            // Note that these atomic orderings come from 1024cores
+            let slot = slot as *mut _;
+            let prev = self.head.swap(slot, AcqRel);
+            (*prev).next_ready_to_run.store(slot, Release);
+        }
+    }
+
+    /// Note that this is unsafe as it required mutual exclusion (only one
+    /// thread can call this) to be guaranteed elsewhere.
+    unsafe fn dequeue(&self) -> Dequeue {
+        let mut tail = *self.tail.get();
+        let mut next = (*tail).next_ready_to_run.load(Acquire);
+
+        if ptr::eq(tail, &self.stub) {
+            if next.is_null() {
+                return Dequeue::Empty;
+            }
+
+            *self.tail.get() = next;
+            tail = next;
+            next = (*next).next_ready_to_run.load(Acquire);
+        }
+
+        if !next.is_null() {
+            *self.tail.get() = next;
+            debug_assert!(!ptr::eq(tail, &self.stub));
+            return Dequeue::Data(tail);
+        }
+
+        if !ptr::eq(self.head.load(Acquire), tail) {
+            return Dequeue::Inconsistent;
+        }
+
+        self.enqueue(&self.stub);
+
+        next = (*tail).next_ready_to_run.load(Acquire);
+
+        if !next.is_null() {
+            *self.tail.get() = next;
+            return Dequeue::Data(tail);
+        }
+
+        Dequeue::Inconsistent
+    }
+}
+
+/// The wake-up state for a [`StaticStreamUnordered`] with room for `N` streams.
+///
+/// Wakers handed out by a `StaticStreamUnordered` may outlive the set itself, so the state they
+/// refer to must live forever. This type holds that state, and is meant to be placed in a
+/// `static`:
+///
+/// ```rust
+/// # use streamunordered::{StaticReadyQueue, StaticStreamUnordered};
+/// # use futures::stream;
+/// # use std::pin::Pin;
+/// static QUEUE: StaticReadyQueue<4> = StaticReadyQueue::new();
+///
+/// let mut streams = StaticStreamUnordered::new(&QUEUE);
+/// let token = Pin::new(&mut streams).push(stream::iter(vec![1, 2, 3])).unwrap();
+/// ```
+///
+/// A queue can only be used by one `StaticStreamUnordered` at a time, but can be reused once the
+/// set that was using it has been dropped.
+pub struct StaticReadyQueue<const N: usize> {
+    raw: RawQueue,
+    slots: [Slot; N],
+    in_use: AtomicBool,
+}
+
+// The `tail` of the queue is only touched by the `StaticStreamUnordered` that has exclusively
+// claimed the queue through `in_use`. Everything else is atomic.
+unsafe impl<const N: usize> Sync for StaticReadyQueue<N> {}
+
+impl<const N: usize> StaticReadyQueue<N> {
+    /// Constructs the wake-up state for a set of up to `N` streams.
+    pub const fn new() -> Self {
+        // Slots start out as queued so that wakers can never enqueue a slot before a stream has
+        // been pushed into it.
+        #[allow(clippy::declare_interior_mutable_const)]
+        const SLOT: Slot = Slot::new(true);
+
+        StaticReadyQueue {
+            raw: RawQueue {
+                waker: AtomicWaker::new(),
+                head: AtomicPtr::new(ptr::null_mut()),
+                tail: UnsafeCell::new(ptr::null()),
+                stub: Slot::new(true),
+            },
+            slots: [SLOT; N],
+            in_use: AtomicBool::new(false),
+        }
+    }
+
+    fn index_of(&self, slot: *const Slot) -> usize {
+        (slot as usize - self.slots.as_ptr() as usize) / mem::size_of::<Slot>()
+    }
+}
+
+impl<const N: usize> Default for StaticReadyQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Debug for StaticReadyQueue<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StaticReadyQueue {{ ... }}")
+    }
+}
+
+/// A set of at most `N` streams which may yield items in any order, and which never allocates.
+///
+/// This behaves like [`StreamUnordered`](crate::StreamUnordered), except that the streams are
+/// stored inline, and [`push`](StaticStreamUnordered::push) hands the stream back if the set is
+/// already full. Tokens are always in `0..N`, and, as with `StreamUnordered`, the token of a
+/// stream that has been removed may be reused for new streams that are added.
+///
+/// Each set needs a [`StaticReadyQueue`] of the same capacity to keep track of which streams have
+/// been woken up.
+#[must_use = "streams do nothing unless polled"]
+pub struct StaticStreamUnordered<S, const N: usize> {
+    queue: &'static StaticReadyQueue<N>,
+    streams: [Option<S>; N],
+    is_done: [bool; N],
+    len: usize,
+    terminated: bool,
+}
+
+impl<S, const N: usize> StaticStreamUnordered<S, N> {
+    /// Constructs a new, empty [`StaticStreamUnordered`] that uses the given wake-up state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is already in use by another `StaticStreamUnordered`.
+    pub fn new(queue: &'static StaticReadyQueue<N>) -> Self {
+        assert!(
+            !queue.in_use.swap(true, Acquire),
+            "StaticReadyQueue is already in use by another StaticStreamUnordered"
+        );
+
+        // The first time the queue is used we have to point it at its own stub, and tell the
+        // slots where to find the queue. Later users will find it exactly as the last user left
+        // it, possibly with some slots still queued, which is fine -- those will just cause
+        // spurious polls.
+        if queue.raw.head.load(Relaxed).is_null() {
+            let raw = &queue.raw as *const RawQueue as *mut RawQueue;
+            let stub = &queue.raw.stub as *const Slot as *mut Slot;
+            unsafe { *queue.raw.tail.get() = stub };
+            queue.raw.head.store(stub, Release);
+            for slot in &queue.slots {
+                slot.queue.store(raw, Relaxed);
+                slot.queued.store(false, Release);
+            }
+        }
+
+        StaticStreamUnordered {
+            queue,
+            streams: core::array::from_fn(|_| None),
+            is_done: [false; N],
+            len: 0,
+            terminated: false,
+        }
+    }
+
+    /// Returns the number of streams contained in the set.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the set contains no streams.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns `true` if the set cannot hold any more streams.
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Push a stream into the set.
+    ///
+    /// Returns the stream's token, or gives the stream back if the set is full.
+    ///
+    /// Note that, unlike the other sets, this set has no stub task occupying token 0, so the first
+    /// stream that is pushed gets token 0.
+    ///
+    /// Just like [`StreamUnordered::push`](crate::StreamUnordered::push), this does not poll the
+    /// stream; [`poll_next`](Stream::poll_next) must be called to start receiving wake-ups for it.
+    pub fn push(self: Pin<&mut Self>, stream: S) -> Result<usize, S> {
+        // Safety: we only write into a vacant slot, so no pinned stream is moved.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        let token = match this.streams.iter().position(Option::is_none) {
+            Some(token) => token,
+            None => return Err(stream),
+        };
+
+        this.streams[token] = Some(stream);
+        this.is_done[token] = false;
+        this.len += 1;
+        this.terminated = false;
+
+        // Make sure the new stream gets polled. If the slot is already queued (because a waker
+        // for a stream that previously had this token fired), it will be polled anyway.
+        let slot = &this.queue.slots[token];
+        if !slot.queued.swap(true, SeqCst) {
+            this.queue.raw.enqueue(slot);
+        }
+
+        Ok(token)
+    }
+
+    /// Remove a stream from the set.
+    ///
+    /// The stream will be dropped and will no longer yield stream events.
+    pub fn remove(self: Pin<&mut Self>, token: usize) -> bool {
+        // Safety: the stream is dropped in place.
+        let this = unsafe { self.get_unchecked_mut() };
+        match this.streams.get_mut(token) {
+            Some(s @ Some(_)) => {
+                *s = None;
+                this.len -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove and return a stream from the set.
+    ///
+    /// Note that since this method moves `S`, which we may have given out a `Pin` to, it requires
+    /// that `S` is `Unpin`.
+    pub fn take(mut self: Pin<&mut Self>, token: usize) -> Option<S>
+    where
+        S: Unpin,
+    {
+        let stream = self.streams.get_mut(token)?.take()?;
+        self.len -= 1;
+        Some(stream)
+    }
+
+    /// Returns `true` if the stream with the given token has yielded `None`.
+    pub fn is_finished(&self, token: usize) -> Option<bool> {
+        self.streams.get(token)?.as_ref()?;
+        Some(self.is_done[token])
+    }
+
+    /// Returns a reference to the stream with the given token
+    pub fn get(&self, token: usize) -> Option<&S> {
+        self.streams.get(token)?.as_ref()
+    }
+
+    /// Returns a reference that allows modifying the stream with the given token.
+    pub fn get_mut(&mut self, token: usize) -> Option<&mut S>
+    where
+        S: Unpin,
+    {
+        self.streams.get_mut(token)?.as_mut()
+    }
+
+    /// Returns a pinned reference that allows modifying the stream with the given token.
+    pub fn get_pin_mut(self: Pin<&mut Self>, token: usize) -> Option<Pin<&mut S>> {
+        // Safety: the streams never move while in the set.
+        unsafe {
+            let this = self.get_unchecked_mut();
+            Some(Pin::new_unchecked(this.streams.get_mut(token)?.as_mut()?))
+        }
+    }
+}
+
+impl<S, const N: usize> Index<usize> for StaticStreamUnordered<S, N> {
+    type Output = S;
+
+    fn index(&self, stream: usize) -> &Self::Output {
+        self.get(stream).unwrap()
+    }
+}
+
+impl<S: Unpin, const N: usize> IndexMut<usize> for StaticStreamUnordered<S, N> {
+    fn index_mut(&mut self, stream: usize) -> &mut Self::Output {
+        self.get_mut(stream).unwrap()
+    }
+}
+
+impl<S, const N: usize> StreamSet for StaticStreamUnordered<S, N> {
+    type Stream = S;
+
+    fn remove_stream(self: Pin<&mut Self>, token: usize) -> bool {
+        self.remove(token)
+    }
+}
+
+impl<S: Unpin, const N: usize> TakeStream for StaticStreamUnordered<S, N> {
+    fn take_stream(self: Pin<&mut Self>, token: usize) -> Option<S> {
+        self.take(token)
+    }
+}
+
+impl<S: Stream, const N: usize> Stream for StaticStreamUnordered<S, N> {
+    type Item = (StreamYield<S>, usize);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Safety: we never move any of the streams.
+        let this = unsafe { self.get_unchecked_mut() };
+        let queue = this.queue;
+
+        queue.raw.waker.register(cx.waker());
+
+        loop {
+            // Safety: we have exclusively claimed the queue, and &mut self guarantees the
+            // mutual exclusion `dequeue` expects.
+            let slot = match unsafe { queue.raw.dequeue() } {
+                Dequeue::Empty => {
+                    if this.is_empty() {
+                        // We can only consider ourselves terminated once we
+                        // have yielded a `None`
+                        this.terminated = true;
+                        return Poll::Ready(None);
+                    } else {
+                        return Poll::Pending;
+                    }
+                }
+                Dequeue::Inconsistent => {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Dequeue::Data(slot) => slot,
+            };
+
+            let token = queue.index_of(slot);
+
+            // Unset queued flag: This must be done before polling to ensure
+            // that the stream gets rescheduled if it sends a wake-up
+            // notification **during** the call to `poll`.
+            //
+            // Unlike for `StreamUnordered`, we also do this for slots that no
+            // longer hold a stream, since the slot will be reused.
+            let prev = queue.slots[token].queued.swap(false, SeqCst);
+            assert!(prev);
+
+            let stream = match this.streams[token] {
+                Some(ref mut stream) => stream,
+                None => continue,
+            };
+
+            if this.is_done[token] {
+                // This stream has already been polled to completion.
+                // We're keeping it around because the user has not removed it yet.
+                continue;
+            }
+
+            let waker = queue.slots[token].waker();
+            let mut cx = Context::from_waker(&waker);
+
+            // Safety: We won't move the stream ever again
+            let stream = unsafe { Pin::new_unchecked(stream) };
+
+            match stream.poll_next(&mut cx) {
+                Poll::Pending => continue,
+                Poll::Ready(None) => {
+                    // The stream has completed -- let the user know, but leave
+                    // it to them to remove it.
+                    this.is_done[token] = true;
+                    return Poll::Ready(Some((
+                        StreamYield::Finished(FinishedStream { token }),
+                        token,
+                    )));
+                }
+                Poll::Ready(Some(output)) => {
+                    // We're going to need to poll it again!
+                    waker.wake();
+                    return Poll::Ready(Some((StreamYield::Item(output), token)));
+                }
+            }
+        }
+    }
+}
+
+impl<S: Stream, const N: usize> FusedStream for StaticStreamUnordered<S, N> {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+impl<S, const N: usize> Debug for StaticStreamUnordered<S, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StaticStreamUnordered {{ ... }}")
+    }
+}
+
+impl<S, const N: usize> Drop for StaticStreamUnordered<S, N> {
+    fn drop(&mut self) {
+        // Drop the streams in place before we give up our claim on the queue, so that the next
+        // user of the queue does not observe any wake-ups from them while they're being dropped.
+        for stream in &mut self.streams {
+            *stream = None;
+        }
+        // The queue outlives us, so it would otherwise hold on to the last waker we registered.
+        self.queue.raw.waker.take();
+        self.queue.in_use.store(false, Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, stream::StreamExt};
+
+    #[test]
+    fn full() {
+        static QUEUE: StaticReadyQueue<2> = StaticReadyQueue::new();
+        let mut s = StaticStreamUnordered::new(&QUEUE);
+        let mut s = Pin::new(&mut s);
+        assert_eq!(s.as_mut().push(stream::iter(vec![0])).ok(), Some(0));
+        assert_eq!(s.as_mut().push(stream::iter(vec![1])).ok(), Some(1));
+        assert!(s.as_mut().push(stream::iter(vec![2])).is_err());
+        assert!(s.as_mut().remove(0));
+        assert_eq!(s.as_mut().push(stream::iter(vec![2])).ok(), Some(0));
+    }
+
+    #[test]
+    fn drives_all_streams() {
+        static QUEUE: StaticReadyQueue<3> = StaticReadyQueue::new();
+        for _ in 0..2 {
+            // the second time around, the queue is reused
+            let mut s = StaticStreamUnordered::new(&QUEUE);
+            let mut s = Pin::new(&mut s);
+            let a = s.as_mut().push(stream::iter(vec![1, 2])).unwrap();
+            let b = s.as_mut().push(stream::iter(vec![3])).unwrap();
+            let mut items = 0;
+            let mut finished = 0;
+            while let Some((y, token)) = futures::executor::block_on(s.next()) {
+                match y {
+                    StreamYield::Item(_) => items += 1,
+                    StreamYield::Finished(f) => {
+                        assert!(token == a || token == b);
+                        finished += 1;
+                        f.remove(s.as_mut());
+                    }
+                    StreamYield::Added(_) => unreachable!(),
+                }
+            }
+            assert_eq!(items, 3);
+            assert_eq!(finished, 2);
+        }
+    }
+
+    #[test]
+    fn drop_releases_waker() {
+        use futures::task::{waker, ArcWake};
+        use std::sync::Arc;
+
+        struct Noop;
+        impl ArcWake for Noop {
+            fn wake_by_ref(_: &Arc<Self>) {}
+        }
+
+        static QUEUE: StaticReadyQueue<1> = StaticReadyQueue::new();
+        let noop = Arc::new(Noop);
+        let w = waker(noop.clone());
+        let mut cx = Context::from_waker(&w);
+        let mut set = StaticStreamUnordered::new(&QUEUE);
+        let mut s = Pin::new(&mut set);
+        s.as_mut().push(stream::pending::<()>()).unwrap();
+        assert!(s.as_mut().poll_next(&mut cx).is_pending());
+        drop(w);
+        assert_eq!(Arc::strong_count(&noop), 2);
+        drop(set);
+        assert_eq!(Arc::strong_count(&noop), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn exclusive() {
+        static QUEUE: StaticReadyQueue<1> = StaticReadyQueue::new();
+        let _a = StaticStreamUnordered::<stream::Empty<()>, 1>::new(&QUEUE);
+        let _b = StaticStreamUnordered::<stream::Empty<()>, 1>::new(&QUEUE);
+    }
+}
@@ -0,0 +1,231 @@
+//! Taking in new streams from a stream of streams.
+
+use super::{StreamUnordered, StreamYield};
+use core::pin::Pin;
+use futures_core::stream::{FusedStream, Stream};
+use futures_core::task::{Context, Poll};
+
+/// A [`StreamUnordered`] that takes in every stream yielded by a source, such as the connections
+/// accepted by a listener.
+///
+/// Each stream the source yields is pushed into the set, and [`StreamYield::Added`] is yielded
+/// with its token, so that any state that goes along with the stream can be set up. Apart from
+/// that, this yields the same events as the underlying set. It does not terminate while it has a
+/// source, even if the set is empty. Once the source yields `None`, it is dropped.
+///
+/// The source is polled at most once per call to [`poll_next`](Stream::poll_next), and after a
+/// stream has been added, the streams already in the set go first on the next call, so a busy
+/// source cannot starve them.
+///
+/// Created with [`StreamUnordered::from_incoming`] or [`StreamUnordered::attach_source`].
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Incoming<S, I> {
+    inner: StreamUnordered<S>,
+    source: Option<I>,
+    // A stream was just added, so the set goes first on the next call.
+    added: bool,
+}
+
+impl<S: Stream> StreamUnordered<S> {
+    /// Constructs a new, empty set that takes in every stream yielded by `incoming`.
+    ///
+    /// See [`Incoming`].
+    pub fn from_incoming<I>(incoming: I) -> Incoming<S, I>
+    where
+        I: Stream<Item = S>,
+    {
+        StreamUnordered::new().attach_source(incoming)
+    }
+}
+
+impl<S> StreamUnordered<S> {
+    /// Makes the set take in every stream yielded by `source`, see [`Incoming`].
+    pub fn attach_source<I>(self, source: I) -> Incoming<S, I>
+    where
+        I: Stream<Item = S>,
+    {
+        Incoming {
+            inner: self,
+            source: Some(source),
+            added: false,
+        }
+    }
+}
+
+impl<S, I> Incoming<S, I> {
+    /// Returns a reference to the underlying set.
+    pub fn get_ref(&self) -> &StreamUnordered<S> {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying set.
+    pub fn get_mut(&mut self) -> &mut StreamUnordered<S> {
+        &mut self.inner
+    }
+
+    /// Returns a pinned mutable reference to the underlying set.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut StreamUnordered<S>> {
+        // Safety: only the source is pinned structurally.
+        Pin::new(unsafe { &mut self.get_unchecked_mut().inner })
+    }
+
+    /// Drops the source of new streams, if it is still there.
+    ///
+    /// Returns `true` if there was a source.
+    pub fn detach_source(self: Pin<&mut Self>) -> bool {
+        // Safety: the source is dropped in place, not moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        let had_source = this.source.is_some();
+        this.source = None;
+        had_source
+    }
+
+    /// Returns the underlying set, dropping the source.
+    pub fn into_inner(self) -> StreamUnordered<S> {
+        self.inner
+    }
+}
+
+impl<S, I> Incoming<S, I>
+where
+    S: Stream,
+    I: Stream<Item = S>,
+{
+    /// Polls the source once, and pushes the stream it yields, if any.
+    ///
+    /// Returns the token of the new stream.
+    fn poll_source(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Option<usize> {
+        // Safety: the source is never moved out of `self`, and the set is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let source = unsafe { Pin::new_unchecked(this.source.as_mut()?) };
+        match source.poll_next(cx) {
+            Poll::Ready(Some(stream)) => {
+                this.added = true;
+                Some(this.inner.push(stream))
+            }
+            Poll::Ready(None) => {
+                this.source = None;
+                None
+            }
+            Poll::Pending => None,
+        }
+    }
+}
+
+impl<S, I> Stream for Incoming<S, I>
+where
+    S: Stream,
+    I: Stream<Item = S>,
+{
+    type Item = (StreamYield<S>, usize);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Take in a new stream first, unless we just did.
+        let polled_source = !self.added;
+        if polled_source {
+            if let Some(token) = self.as_mut().poll_source(cx) {
+                return Poll::Ready(Some((StreamYield::Added(token), token)));
+            }
+        } else {
+            // Safety: `added` is not pinned.
+            unsafe { self.as_mut().get_unchecked_mut().added = false };
+        }
+
+        let res = self.as_mut().get_pin_mut().poll_next(cx);
+        if let Poll::Ready(Some(next)) = res {
+            return Poll::Ready(Some(next));
+        }
+
+        // The set has nothing for us, so the source has to be able to wake us up too.
+        if !polled_source {
+            if let Some(token) = self.as_mut().poll_source(cx) {
+                return Poll::Ready(Some((StreamYield::Added(token), token)));
+            }
+        }
+
+        if res.is_ready() && self.source.is_none() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<S, I> FusedStream for Incoming<S, I>
+where
+    S: Stream,
+    I: Stream<Item = S>,
+{
+    fn is_terminated(&self) -> bool {
+        self.source.is_none() && self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::FutureExt;
+    use futures_util::stream::{self, StreamExt};
+
+    #[test]
+    fn from_incoming() {
+        use futures::channel::mpsc;
+
+        let (tx, rx) = mpsc::unbounded();
+        let mut s = StreamUnordered::from_incoming(rx);
+
+        // no streams yet, but more may come
+        assert!(s.next().now_or_never().is_none());
+
+        tx.unbounded_send(stream::iter(vec![1])).unwrap();
+        let a = match s.next().now_or_never() {
+            Some(Some((StreamYield::Added(a), t))) if a == t => a,
+            y => panic!("{:?}", y),
+        };
+        assert_eq!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Item(1), a)))
+        );
+        assert!(matches!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Finished(_), t))) if t == a
+        ));
+
+        // the set terminates once the source is done and the streams are gone
+        drop(tx);
+        assert!(Pin::new(s.get_mut()).remove(a));
+        assert_eq!(s.next().now_or_never(), Some(None));
+        assert!(s.is_terminated());
+        assert!(!Pin::new(&mut s).detach_source());
+    }
+
+    #[test]
+    fn busy_source() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // a source that always has another stream ready, and counts how often it is polled
+        let polls = Rc::new(Cell::new(0));
+        let p = Rc::clone(&polls);
+        let source = stream::poll_fn(move |_| {
+            p.set(p.get() + 1);
+            Poll::Ready(Some(stream::iter(vec![0])))
+        });
+        let mut s = Box::pin(StreamUnordered::from_incoming(source));
+
+        let mut items = 0;
+        for calls in 1..=10 {
+            match s.next().now_or_never() {
+                Some(Some((StreamYield::Item(_), _))) => items += 1,
+                Some(Some(_)) => {}
+                y => panic!("{:?}", y),
+            }
+            assert!(s.source.is_some());
+            assert!(polls.get() <= calls);
+        }
+
+        // the streams that were added get polled in between
+        assert!(items >= 3);
+    }
+}
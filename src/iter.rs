@@ -1,5 +1,5 @@
-use super::task::Task;
-use super::StreamUnordered;
+use super::task::{Task, DONE};
+use super::{PinnedStream, StreamState, StreamUnordered};
 use core::marker::PhantomData;
 use core::pin::Pin;
 
@@ -51,3 +51,234 @@ impl<'a, S: Unpin> Iterator for IterMut<'a, S> {
 }
 
 impl<S: Unpin> ExactSizeIterator for IterMut<'_, S> {}
+
+#[derive(Debug)]
+/// Iterator over the token and scheduling state of all streams in the unordered set.
+pub struct IterStates<'a, S> {
+    pub(super) task: *const Task<S>,
+    pub(super) len: usize,
+    pub(super) _marker: PhantomData<&'a StreamUnordered<S>>,
+}
+
+impl<'a, S> Iterator for IterStates<'a, S> {
+    type Item = (usize, StreamState);
+
+    fn next(&mut self) -> Option<(usize, StreamState)> {
+        if self.task.is_null() {
+            return None;
+        }
+        unsafe {
+            let item = ((*self.task).id, Task::state(self.task));
+            self.task = *(*self.task).next_all.get();
+            self.len -= 1;
+            Some(item)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, S> ExactSizeIterator for IterStates<'a, S> {}
+
+#[derive(Debug)]
+/// Immutable iterator over all streams in the unordered set.
+pub struct Iter<'a, S>(pub(super) IterWithTokens<'a, S>);
+
+impl<'a, S> Iterator for Iter<'a, S> {
+    type Item = &'a S;
+
+    fn next(&mut self) -> Option<&'a S> {
+        self.0.next().map(|(_, s)| s)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<S> ExactSizeIterator for Iter<'_, S> {}
+
+#[derive(Debug)]
+/// Immutable iterator over all streams in the unordered set, along with their tokens.
+pub struct IterWithTokens<'a, S> {
+    pub(super) task: *const Task<S>,
+    pub(super) len: usize,
+    pub(super) _marker: PhantomData<&'a StreamUnordered<S>>,
+}
+
+impl<'a, S> Iterator for IterWithTokens<'a, S> {
+    type Item = (usize, &'a S);
+
+    fn next(&mut self) -> Option<(usize, &'a S)> {
+        if self.task.is_null() {
+            return None;
+        }
+        unsafe {
+            let stream = (*(*self.task).stream.get()).as_ref().unwrap();
+            let token = (*self.task).id;
+            self.task = *(*self.task).next_all.get();
+            self.len -= 1;
+            Some((token, stream))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<S> ExactSizeIterator for IterWithTokens<'_, S> {}
+
+#[derive(Debug)]
+/// Mutable iterator over all streams in the unordered set, along with their tokens.
+pub struct IterPinMutWithTokens<'a, S> {
+    pub(super) task: *const Task<S>,
+    pub(super) len: usize,
+    pub(super) _marker: PhantomData<&'a mut StreamUnordered<S>>,
+}
+
+impl<'a, S> Iterator for IterPinMutWithTokens<'a, S> {
+    type Item = (usize, Pin<&'a mut S>);
+
+    fn next(&mut self) -> Option<(usize, Pin<&'a mut S>)> {
+        if self.task.is_null() {
+            return None;
+        }
+        unsafe {
+            let stream = (*(*self.task).stream.get()).as_mut().unwrap();
+            let token = (*self.task).id;
+            self.task = *(*self.task).next_all.get();
+            self.len -= 1;
+            Some((token, Pin::new_unchecked(stream)))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<S> ExactSizeIterator for IterPinMutWithTokens<'_, S> {}
+
+#[derive(Debug)]
+/// Mutable iterator over all streams in the unordered set, along with their tokens.
+pub struct IterMutWithTokens<'a, S: Unpin>(pub(super) IterPinMutWithTokens<'a, S>);
+
+impl<'a, S: Unpin> Iterator for IterMutWithTokens<'a, S> {
+    type Item = (usize, &'a mut S);
+
+    fn next(&mut self) -> Option<(usize, &'a mut S)> {
+        self.0.next().map(|(t, s)| (t, Pin::get_mut(s)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<S: Unpin> ExactSizeIterator for IterMutWithTokens<'_, S> {}
+
+#[derive(Debug)]
+/// Iterator over the tokens of all streams in the unordered set.
+pub struct Tokens<'a, S> {
+    pub(super) task: *const Task<S>,
+    pub(super) len: usize,
+    pub(super) _marker: PhantomData<&'a StreamUnordered<S>>,
+}
+
+impl<'a, S> Iterator for Tokens<'a, S> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.task.is_null() {
+            return None;
+        }
+        unsafe {
+            let token = (*self.task).id;
+            self.task = *(*self.task).next_all.get();
+            self.len -= 1;
+            Some(token)
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<S> ExactSizeIterator for Tokens<'_, S> {}
+
+#[derive(Debug)]
+/// Iterator over the tokens of either only the finished or only the active streams in the
+/// unordered set.
+pub struct FilteredTokens<'a, S> {
+    pub(super) task: *const Task<S>,
+    pub(super) len: usize,
+    pub(super) finished: bool,
+    pub(super) _marker: PhantomData<&'a StreamUnordered<S>>,
+}
+
+impl<'a, S> Iterator for FilteredTokens<'a, S> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while !self.task.is_null() {
+            unsafe {
+                let task = self.task;
+                self.task = *(*task).next_all.get();
+                self.len -= 1;
+                if (*task).has(DONE) == self.finished {
+                    return Some((*task).id);
+                }
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.len))
+    }
+}
+
+#[derive(Debug)]
+/// Owning iterator over all streams in the unordered set, along with their tokens.
+///
+/// Any streams that are not yielded are dropped along with the iterator.
+pub struct IntoStreams<S: Unpin>(pub(super) StreamUnordered<S>);
+
+impl<S: Unpin> Iterator for IntoStreams<S> {
+    type Item = (usize, S);
+
+    fn next(&mut self) -> Option<(usize, S)> {
+        self.0.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl<S: Unpin> ExactSizeIterator for IntoStreams<S> {}
+
+#[derive(Debug)]
+/// Owning iterator over all streams in the unordered set, along with their tokens, which leaves
+/// the streams pinned in place.
+///
+/// Any streams that are not yielded are dropped along with the iterator.
+pub struct IntoPinnedStreams<S>(pub(super) StreamUnordered<S>);
+
+impl<S> Iterator for IntoPinnedStreams<S> {
+    type Item = (usize, PinnedStream<S>);
+
+    fn next(&mut self) -> Option<(usize, PinnedStream<S>)> {
+        self.0.pop_pinned()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl<S> ExactSizeIterator for IntoPinnedStreams<S> {}
@@ -27,8 +27,45 @@
 //! This value indicates that an underlying stream (the one indicated by the included index)
 //! produced an item. If an underlying stream yields `Poll::Ready(None)` to indicate termination,
 //! a `StreamYield::Finished` is returned instead. Note that as soon as a stream returns
-//! `StreamYield::Finished`, its token may be reused for new streams that are added.
+//! `StreamYield::Finished`, its token may be reused for new streams that are added. If the set
+//! was given a source of new streams with [`StreamUnordered::from_incoming`], the resulting
+//! [`Incoming`] returns a `StreamYield::Added` whenever a stream from that source is pushed into
+//! the set.
+//!
+//! If all the managed streams live on a single thread, and are only ever woken up from that
+//! thread, [`LocalStreamUnordered`] provides the same interface without any atomic operations on
+//! the wake-up path.
+//!
+//! To manage streams of different types that all yield the same item type,
+//! [`DynStreamUnordered`] avoids having to box each stream on top of the allocation every
+//! managed stream already gets. It can also hold one-shot futures alongside the streams, see
+//! [`DynStreamUnordered::push_future`].
+//!
+//! For targets where allocating on every `push` is not an option, [`StaticStreamUnordered`]
+//! stores up to a fixed number of streams inline, and never allocates.
+//!
+//! # Metrics
+//!
+//! With the `metrics` feature, `StreamUnordered` keeps counters of how often each stream is
+//! polled, woken up, and so on. See [`StreamUnordered::stats`] and
+//! [`StreamUnordered::total_stats`]. To find streams whose `poll_next` blocks and thereby stalls
+//! the other streams in the set, use [`StreamUnordered::set_slow_poll_hook`], which also makes
+//! the set keep track of how long each poll took.
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature, each stream in a `StreamUnordered` gets a [`tracing`] span with
+//! its token (and optionally a label, see [`StreamUnordered::push_labeled`]) that is entered
+//! whenever the stream is polled. Events are emitted in that span when the stream finishes,
+//! panics, is woken up only to return `Poll::Pending`, or is removed from the set.
+//!
+//! # `no_std` support
+//!
+//! This crate only depends on `core` and `alloc`. The default `std` feature can be disabled to
+//! use it on targets without the standard library. [`LocalStreamUnordered`] relies on
+//! thread-locals, and is therefore only available with the `std` feature.
 
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![deny(missing_docs)]
 #![warn(rust_2018_idioms)]
 
@@ -37,7 +74,10 @@
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::fmt::{self, Debug};
 use core::iter::FromIterator;
@@ -50,27 +90,64 @@ use core::sync::atomic::Ordering::SeqCst;
 use core::sync::atomic::{AtomicBool, AtomicPtr};
 use futures_core::stream::{FusedStream, Stream};
 use futures_core::task::{Context, Poll};
-use futures_util::task::{ArcWake, AtomicWaker};
+use futures_util::task::AtomicWaker;
 
 mod abort;
 
 mod iter;
-pub use self::iter::{IterMut, IterPinMut};
+pub use self::iter::{
+    FilteredTokens, IntoPinnedStreams, IntoStreams, Iter, IterMut, IterMutWithTokens, IterPinMut,
+    IterPinMutWithTokens, IterStates, IterWithTokens, Tokens,
+};
 
 mod task;
-use self::task::Task;
+use self::task::{Task, BACKLOGGED, DONE, PARKED, PAUSED};
+
+mod close;
+pub use self::close::{CloseAll, CloseError};
+
+mod duplex;
+pub use self::duplex::{DuplexUnordered, DuplexYield, OverflowPolicy, SendError};
+
+mod sequence;
+pub use self::sequence::{Sequence, Sequenced};
+
+mod incoming;
+pub use self::incoming::Incoming;
+
+mod merge;
+pub use self::merge::StreamOrderedMerge;
+
+mod observer;
+pub use self::observer::Observer;
 
 mod ready_to_run_queue;
 use self::ready_to_run_queue::{Dequeue, ReadyToRunQueue};
 
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use self::metrics::{PollHistogram, Stats, StreamStats};
+
+mod dynamic;
+pub use self::dynamic::{DynStream, DynStreamEntry, DynStreamUnordered, LocalDynStreamUnordered};
+
+mod fixed;
+pub use self::fixed::{StaticReadyQueue, StaticStreamUnordered};
+
+#[cfg(feature = "std")]
+mod local;
+#[cfg(feature = "std")]
+pub use self::local::{LocalIterMut, LocalIterPinMut, LocalStreamEntry, LocalStreamUnordered};
+
 /// Constant used for a `StreamUnordered` to indicate we are empty and have
 /// yielded a `None` element so can return `true` from
 /// `FusedStream::is_terminated`
 ///
 /// It is safe to not check for this when incrementing as even a ZST stream will
-/// have a `Task` allocated for it, so we cannot ever reach usize::max_value()
+/// have a `Task` allocated for it, so we cannot ever reach usize::MAX
 /// without running out of ram.
-const TERMINATED_SENTINEL_LENGTH: usize = usize::max_value();
+const TERMINATED_SENTINEL_LENGTH: usize = usize::MAX;
 
 /// A set of streams which may yield items in any order.
 ///
@@ -91,12 +168,30 @@ const TERMINATED_SENTINEL_LENGTH: usize = usize::max_value();
 /// with the [`StreamUnordered::new`] constructor.
 #[must_use = "streams do nothing unless polled"]
 pub struct StreamUnordered<S> {
-    ready_to_run_queue: Arc<ReadyToRunQueue<S>>,
+    ready_to_run_queue: Arc<ReadyToRunQueue<Task<S>>>,
     len: usize,
     head_all: *const Task<S>,
     by_id: slab::Slab<*const Task<S>>,
+    free_tasks: Vec<Arc<Task<S>>>,
+    recycle_limit: usize,
+    observer: Option<Box<dyn Observer + Send>>,
+    closing: bool,
+    arrivals: Option<u64>,
+    max_active: Option<usize>,
+    active: usize,
+    backlog: VecDeque<usize>,
+    #[cfg(feature = "metrics")]
+    retired: Stats,
+    #[cfg(feature = "metrics")]
+    slow_poll: Option<SlowPollHook>,
 }
 
+#[cfg(feature = "metrics")]
+type SlowPollHook = (
+    std::time::Duration,
+    Box<dyn FnMut(usize, std::time::Duration) + Send>,
+);
+
 unsafe impl<S: Send> Send for StreamUnordered<S> {}
 unsafe impl<S: Sync> Sync for StreamUnordered<S> {}
 impl<S> Unpin for StreamUnordered<S> {}
@@ -123,6 +218,12 @@ impl<S> Unpin for StreamUnordered<S> {}
 // whether the task is currently inserted in the atomic queue. When a wake-up
 // notifiaction is received, the task will only be inserted into the ready to
 // run queue if it isn't inserted already.
+//
+// Once a task has been released and no wakers refer to it any more, its
+// allocation may be kept in a free list (up to `recycle_limit` of them) so that
+// the next stream that is pushed can reuse it. If wakers outlive the stream,
+// the last one to be dropped hands the task back through the ready to run
+// queue.
 
 /// A handle to an vacant stream slot in a `StreamUnordered`.
 ///
@@ -147,6 +248,9 @@ impl<'a, S: 'a> StreamEntry<'a, S> {
         unsafe {
             (*(*self.backref.by_id[self.token]).stream.get()) = Some(stream);
         }
+
+        let token = self.token;
+        self.backref.notify(|o| o.on_insert(token));
     }
 
     /// Return the token associated with this slot.
@@ -155,18 +259,33 @@ impl<'a, S: 'a> StreamEntry<'a, S> {
     pub fn token(&self) -> usize {
         self.token
     }
+
+    /// Record a label for the stream in this slot in its tracing span.
+    ///
+    /// Only available with the `tracing` feature.
+    #[cfg(feature = "tracing")]
+    pub fn label(&self, label: &str) {
+        // this is safe for the same reason as in `insert`
+        unsafe {
+            (*self.backref.by_id[self.token])
+                .span
+                .record("label", label)
+        };
+    }
 }
 
 impl<'a, S: 'a> Drop for StreamEntry<'a, S> {
     fn drop(&mut self) {
         if !self.inserted {
             // undo the insertion
-            let task_ptr = self.backref.by_id.remove(self.token);
+            let task_ptr = self.backref.by_id[self.token];
 
             // we know task_ptr points to a valid task, since the StreamEntry
             // has held the &mut StreamUnordered the entire time.
             let task = unsafe { self.backref.unlink(task_ptr) };
             self.backref.release_task(task);
+            let token = self.token;
+            self.backref.notify(|o| o.on_vacate(token));
         }
     }
 }
@@ -178,18 +297,19 @@ impl<S: Stream> StreamUnordered<S> {
     /// In this state, [`StreamUnordered::poll_next`](Stream::poll_next) will
     /// return [`Poll::Ready(None)`](Poll::Ready).
     pub fn new() -> StreamUnordered<S> {
-        let mut slab = slab::Slab::new();
+        StreamUnordered::with_capacity(0)
+    }
+
+    /// Constructs a new, empty [`StreamUnordered`] with room for at least `capacity` streams.
+    ///
+    /// The set will be able to hold `capacity` streams without growing its token table. Note
+    /// that every stream still needs a task allocated for it when it is pushed, unless tasks
+    /// have been set aside with [`StreamUnordered::reserve_tasks`].
+    pub fn with_capacity(capacity: usize) -> StreamUnordered<S> {
+        // one extra slot for the stub
+        let mut slab = slab::Slab::with_capacity(capacity + 1);
         let slot = slab.vacant_entry();
-        let stub = Arc::new(Task {
-            stream: UnsafeCell::new(None),
-            is_done: UnsafeCell::new(false),
-            next_all: UnsafeCell::new(ptr::null()),
-            prev_all: UnsafeCell::new(ptr::null()),
-            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
-            queued: AtomicBool::new(true),
-            ready_to_run_queue: Weak::new(),
-            id: slot.key(),
-        });
+        let stub = Arc::new(Task::new(Weak::new(), slot.key()));
 
         let stub_ptr = &*stub as *const Task<S>;
         let _ = slab.insert(stub_ptr);
@@ -199,6 +319,7 @@ impl<S: Stream> StreamUnordered<S> {
             head: AtomicPtr::new(stub_ptr as *mut _),
             tail: UnsafeCell::new(stub_ptr),
             stub,
+            recycling: AtomicBool::new(false),
         });
 
         StreamUnordered {
@@ -206,10 +327,146 @@ impl<S: Stream> StreamUnordered<S> {
             head_all: ptr::null_mut(),
             ready_to_run_queue,
             by_id: slab,
+            free_tasks: Vec::new(),
+            recycle_limit: 0,
+            observer: None,
+            closing: false,
+            arrivals: None,
+            max_active: None,
+            active: 0,
+            backlog: VecDeque::new(),
+            #[cfg(feature = "metrics")]
+            retired: Stats::default(),
+            #[cfg(feature = "metrics")]
+            slow_poll: None,
         }
     }
 }
 
+impl<S: Stream> StreamUnordered<S> {
+    /// Polls only the stream with the given token.
+    ///
+    /// This is useful for driving a single stream (say, while waiting for a reply on a particular
+    /// connection) without touching the other streams in the set. The stream is polled with the
+    /// same waker it is polled with from [`StreamUnordered::poll_next`](Stream::poll_next), and
+    /// the current task is woken up when _any_ stream in the set is woken up.
+    ///
+    /// The stream is polled even if it is paused. Returns `Poll::Ready(None)` if there is no
+    /// stream with the given token, or if it has already finished.
+    pub fn poll_next_token(
+        mut self: Pin<&mut Self>,
+        token: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<StreamYield<S>>> {
+        if token == 0 {
+            return Poll::Ready(None);
+        }
+
+        let task = match self.by_id.get(token) {
+            Some(&task) => task,
+            None => return Poll::Ready(None),
+        };
+
+        // The stream's wake-ups end up in the ready to run queue, so that's
+        // where our caller needs to hear about them from.
+        self.ready_to_run_queue.waker.register(cx.waker());
+
+        // Safety: we know that by_id only references valid tasks, and the
+        // linked list holds a reference count for it. We only ever access
+        // the task's flags and the stream on the thread that owns
+        // StreamUnordered.
+        let task = mem::ManuallyDrop::new(unsafe { Arc::from_raw(task) });
+        if unsafe { task.has(DONE) } {
+            return Poll::Ready(None);
+        }
+
+        // If the task is in the ready to run queue, we leave it there, and it
+        // will just get polled again. If it was parked, it is _not_ in the
+        // queue, so we reset its queued flag so that it can be woken up again.
+        if unsafe { task.has(PARKED) } {
+            unsafe { task.set(PARKED, false) };
+            task.queued.store(false, SeqCst);
+        }
+
+        // Safety: the stream lives in the task, and is never moved.
+        let res = unsafe {
+            let stream = (*task.stream.get()).as_mut().unwrap();
+            self.poll_task(&task, Pin::new_unchecked(stream))
+        };
+
+        match res {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(None) => {
+                unsafe { task.set(DONE, true) };
+                self.deactivate(&task);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(parent: &task.span, "stream finished");
+                self.notify(|o| o.on_finish(token));
+                Poll::Ready(Some(StreamYield::Finished(FinishedStream { token })))
+            }
+            Poll::Ready(Some(output)) => {
+                // Just like in `poll_next`, make sure the stream gets polled again.
+                Task::schedule(&task);
+                self.notify(|o| o.on_item(token));
+                Poll::Ready(Some(StreamYield::Item(output)))
+            }
+        }
+    }
+
+    /// Polls the stream in the given task with that task's waker.
+    ///
+    /// This is where the stream's poll is timed and traced, if those features are enabled. This
+    /// method is unsafe since it must only be called from the thread that owns
+    /// `StreamUnordered`.
+    unsafe fn poll_task(
+        &mut self,
+        task: &Arc<Task<S>>,
+        stream: Pin<&mut S>,
+    ) -> Poll<Option<S::Item>> {
+        // Only take timestamps if someone is going to look at them.
+        #[cfg(feature = "metrics")]
+        let started = self.slow_poll.as_ref().map(|_| std::time::Instant::now());
+
+        // Wake-ups from here on are for the next poll.
+        #[cfg(feature = "tracing")]
+        let woken = task.woken.swap(false, SeqCst);
+
+        let res = {
+            #[cfg(feature = "tracing")]
+            let _entered = task.span.enter();
+
+            let waker = Task::waker_ref(task);
+            let mut cx = Context::from_waker(&waker);
+            stream.poll_next(&mut cx)
+        };
+
+        #[cfg(feature = "tracing")]
+        if woken && res.is_pending() {
+            tracing::trace!(parent: &task.span, "spurious wakeup");
+        }
+
+        // Keep count for `Sequenced`, if the set is wrapped in one.
+        if let (Poll::Ready(Some(_)), Some(arrivals)) = (&res, self.arrivals.as_mut()) {
+            *task.seq.get() += 1;
+            *arrivals += 1;
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let took = started.map(|started| started.elapsed());
+            task.metrics.polled(&res, took);
+
+            if let (Some(took), Some((threshold, hook))) = (took, self.slow_poll.as_mut()) {
+                if took >= *threshold {
+                    hook(task.id, took);
+                }
+            }
+        }
+
+        res
+    }
+}
+
 impl<S: Stream> Default for StreamUnordered<S> {
     fn default() -> StreamUnordered<S> {
         StreamUnordered::new()
@@ -242,16 +499,36 @@ impl<S> StreamUnordered<S> {
         let slot = self.by_id.vacant_entry();
         let token = slot.key();
 
-        let task = Arc::new(Task {
-            stream: UnsafeCell::new(None),
-            is_done: UnsafeCell::new(false),
-            next_all: UnsafeCell::new(ptr::null_mut()),
-            prev_all: UnsafeCell::new(ptr::null_mut()),
-            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
-            queued: AtomicBool::new(true),
-            ready_to_run_queue: Arc::downgrade(&self.ready_to_run_queue),
-            id: token,
-        });
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("stream", token, label = tracing::field::Empty);
+
+        let task = if let Some(mut task) = self.free_tasks.pop() {
+            // We only ever put tasks in the free list if we hold the only
+            // reference to them, so no-one can have cloned them since.
+            let t = Arc::get_mut(&mut task).expect("recycled task is shared");
+            t.reset_flags();
+            *t.seq.get_mut() = 0;
+            *t.queued.get_mut() = true;
+            t.id = token;
+            #[cfg(feature = "metrics")]
+            {
+                t.metrics = metrics::TaskMetrics::new();
+            }
+            #[cfg(feature = "tracing")]
+            {
+                t.span = span;
+                *t.woken.get_mut() = false;
+            }
+            task
+        } else {
+            #[allow(unused_mut)]
+            let mut task = Task::new(Arc::downgrade(&self.ready_to_run_queue), token);
+            #[cfg(feature = "tracing")]
+            {
+                task.span = span;
+            }
+            Arc::new(task)
+        };
 
         let _ = slot.insert(&*task as *const _);
 
@@ -266,6 +543,17 @@ impl<S> StreamUnordered<S> {
         // and we'll reclaim ownership through the `unlink` method below.
         let ptr = self.link(task);
 
+        // If we already have as many active streams as we're allowed, this one
+        // has to wait its turn. It still goes through the ready to run queue
+        // below, which parks it until it is promoted.
+        if self.max_active.map_or(false, |max| self.active >= max) {
+            // we only ever access the task's flags on the thread that owns StreamUnordered
+            unsafe { (*ptr).set(BACKLOGGED, true) };
+            self.backlog.push_back(token);
+        } else {
+            self.active += 1;
+        }
+
         // We'll need to get the stream "into the system" to start tracking it,
         // e.g. getting its wake-up notifications going to us tracking which
         // streams are ready. To do that we unconditionally enqueue it for
@@ -298,6 +586,20 @@ impl<S> StreamUnordered<S> {
         token
     }
 
+    /// Push a stream into the set, and record `label` in its span.
+    ///
+    /// See [`StreamUnordered::push`] and [`StreamEntry::label`].
+    ///
+    /// Only available with the `tracing` feature.
+    #[cfg(feature = "tracing")]
+    pub fn push_labeled(&mut self, stream: S, label: &str) -> usize {
+        let s = self.stream_entry();
+        let token = s.token();
+        s.label(label);
+        s.insert(stream);
+        token
+    }
+
     /// Remove a stream from the set.
     ///
     /// The stream will be dropped and will no longer yield stream events.
@@ -313,8 +615,7 @@ impl<S> StreamUnordered<S> {
         };
 
         // we know that by_id only references valid tasks
-        let task = unsafe { self.unlink(task) };
-        self.release_task(task);
+        unsafe { self.remove_task(task) };
         true
     }
 
@@ -335,15 +636,185 @@ impl<S> StreamUnordered<S> {
         let task = *self.by_id.get(token)?;
 
         // we know that by_id only references valid tasks
-        let task = unsafe { self.unlink(task) };
+        unsafe { self.take_task(task) }
+    }
+
+    /// Removes every stream for which `f` returns `false`.
+    ///
+    /// `f` is given the token and a pinned reference to each stream in the set, exactly once.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize, Pin<&mut S>) -> bool,
+    {
+        let mut task = self.head_all;
+        while !task.is_null() {
+            // we know that head_all only references valid tasks, and we grab the
+            // next one before we (maybe) unlink this one.
+            unsafe {
+                let next = *(*task).next_all.get();
+                let stream = (*(*task).stream.get()).as_mut().unwrap();
+                if !f((*task).id, Pin::new_unchecked(stream)) {
+                    self.remove_task(task);
+                }
+                task = next;
+            }
+        }
+    }
+
+    /// Removes every stream from the set.
+    ///
+    /// Unlike replacing the set with a new one, this keeps the allocations the set has already
+    /// made, such as its token table and any tasks kept for reuse (see
+    /// [`StreamUnordered::set_recycle_limit`]).
+    ///
+    /// This also ends any [`StreamUnordered::poll_close_all`] that is in progress, so that the
+    /// set reads from the streams that are pushed into it afterwards.
+    pub fn clear(&mut self) {
+        while !self.head_all.is_null() {
+            // we know that head_all only references valid tasks
+            unsafe { self.remove_task(self.head_all) };
+        }
+        self.closing = false;
+    }
+
+    /// Removes every stream from the set, and returns them along with their tokens.
+    ///
+    /// This is the same as [`IntoIterator::into_iter`]. Like [`StreamUnordered::take`], it
+    /// requires that `S` is `Unpin`, since the streams have been pinned. For streams that are not
+    /// `Unpin`, use [`StreamUnordered::into_pinned_streams`].
+    pub fn into_streams(self) -> IntoStreams<S>
+    where
+        S: Unpin,
+    {
+        IntoStreams(self)
+    }
+
+    /// Removes every stream from the set, and returns them along with their tokens, without
+    /// moving them.
+    ///
+    /// Each stream stays where the set pinned it, and is handed out as a [`PinnedStream`] that
+    /// owns its allocation.
+    pub fn into_pinned_streams(self) -> IntoPinnedStreams<S> {
+        IntoPinnedStreams(self)
+    }
+
+    /// Removes the most recently pushed stream without moving it, along with its token.
+    fn pop_pinned(&mut self) -> Option<(usize, PinnedStream<S>)> {
+        if self.head_all.is_null() {
+            return None;
+        }
+
+        // we know that head_all only references valid tasks
+        let task = unsafe { self.unlink(self.head_all) };
+        let token = task.id;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &task.span, "stream taken");
+
+        // The stream stays in the task, which the `PinnedStream` keeps alive.
+        // Since the task's queued flag is now set for good, wakers will leave
+        // it alone, and only the `PinnedStream` touches the stream from here on.
+        let stream = PinnedStream {
+            task: task.clone(),
+            _marker: PhantomData,
+        };
+        if self.disown_task(&task) {
+            mem::forget(task);
+        }
+        self.notify(|o| o.on_remove(token));
+
+        Some((token, stream))
+    }
+
+    /// Removes and returns the most recently pushed stream, along with its token.
+    fn pop(&mut self) -> Option<(usize, S)>
+    where
+        S: Unpin,
+    {
+        if self.head_all.is_null() {
+            return None;
+        }
+
+        // we know that head_all only references valid tasks
+        let token = unsafe { (*self.head_all).id };
+        let stream = unsafe { self.take_task(self.head_all) }?;
+        Some((token, stream))
+    }
+
+    /// Removes every stream that has yielded `None`, and returns their tokens.
+    pub fn drain_finished(&mut self) -> Vec<usize> {
+        let mut tokens = Vec::new();
+        let mut task = self.head_all;
+        while !task.is_null() {
+            // same as in retain
+            unsafe {
+                let next = *(*task).next_all.get();
+                if (*task).has(DONE) {
+                    tokens.push((*task).id);
+                    self.remove_task(task);
+                }
+                task = next;
+            }
+        }
+        tokens
+    }
+
+    /// Removes every stream that has yielded `None`, and returns them along with their tokens.
+    ///
+    /// Like [`StreamUnordered::take`], this requires that `S` is `Unpin`.
+    pub fn drain_finished_streams(&mut self) -> Vec<(usize, S)>
+    where
+        S: Unpin,
+    {
+        let mut streams = Vec::new();
+        let mut task = self.head_all;
+        while !task.is_null() {
+            // same as in retain
+            unsafe {
+                let next = *(*task).next_all.get();
+                if (*task).has(DONE) {
+                    let token = (*task).id;
+                    streams.extend(self.take_task(task).map(|s| (token, s)));
+                }
+                task = next;
+            }
+        }
+        streams
+    }
+
+    /// Removes a task from the set, dropping its stream.
+    ///
+    /// This method is unsafe because it has be guaranteed that `task` is a
+    /// valid pointer to a task in the set.
+    unsafe fn remove_task(&mut self, task: *const Task<S>) {
+        let task = self.unlink(task);
+        let token = task.id;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &task.span, "stream removed");
+        self.release_task(task);
+        self.notify(|o| o.on_remove(token));
+    }
+
+    /// Removes a task from the set, and returns its stream.
+    ///
+    /// This method is unsafe because it has be guaranteed that `task` is a
+    /// valid pointer to a task in the set.
+    unsafe fn take_task(&mut self, task: *const Task<S>) -> Option<S>
+    where
+        S: Unpin,
+    {
+        let task = self.unlink(task);
+        let token = task.id;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &task.span, "stream taken");
 
         // This is safe because we're dropping the stream on the thread that owns
         // `StreamUnordered`, which correctly tracks `S`'s lifetimes and such.
         // The logic is the same as for why release_task is allowed to touch task.stream.
         // Since S: Unpin, it is okay for us to move S.
-        let stream = unsafe { &mut *task.stream.get() }.take();
+        let stream = (*task.stream.get()).take();
 
         self.release_task(task);
+        self.notify(|o| o.on_remove(token));
 
         stream
     }
@@ -355,11 +826,139 @@ impl<S> StreamUnordered<S> {
         }
 
         // we know that by_id only references valid tasks
-        Some(unsafe { (*(**self.by_id.get(token)?).is_done.get()) })
+        Some(unsafe { (**self.by_id.get(token)?).has(DONE) })
+    }
+
+    /// Stops polling the stream with the given token until it is resumed.
+    ///
+    /// Wake-ups the stream receives while paused are not lost, they just take effect once
+    /// [`StreamUnordered::resume`] is called. Returns `false` if there is no stream with the given
+    /// token.
+    pub fn pause(&mut self, token: usize) -> bool {
+        if token == 0 {
+            return false;
+        }
+
+        match self.by_id.get(token) {
+            // we know that by_id only references valid tasks
+            Some(&task) => {
+                unsafe { (*task).set(PAUSED, true) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resumes polling the stream with the given token after [`StreamUnordered::pause`].
+    ///
+    /// If the stream was woken up while it was paused, it will be polled on the next call to
+    /// [`StreamUnordered::poll_next`](Stream::poll_next). Returns `false` if there is no stream
+    /// with the given token.
+    pub fn resume(&mut self, token: usize) -> bool {
+        if token == 0 {
+            return false;
+        }
+
+        let task = match self.by_id.get(token) {
+            Some(&task) => task,
+            None => return false,
+        };
+
+        // we know that by_id only references valid tasks, and the task's
+        // flags are only ever accessed on the thread that owns StreamUnordered.
+        unsafe {
+            (*task).set(PAUSED, false);
+            if (*task).has(PARKED) && !(*task).has(DONE | BACKLOGGED) {
+                // The task was taken off the queue while paused, but its
+                // queued flag is still set, so no-one else will enqueue it.
+                (*task).set(PARKED, false);
+                self.ready_to_run_queue.enqueue(task);
+            }
+        }
+        true
+    }
+
+    /// Returns `true` if the stream with the given token is paused.
+    pub fn is_paused(&self, token: usize) -> Option<bool> {
+        if token == 0 {
+            return None;
+        }
+
+        // we know that by_id only references valid tasks
+        Some(unsafe { (**self.by_id.get(token)?).has(PAUSED) })
+    }
+
+    /// Wakes up the stream with the given token, as if its waker had been woken.
+    ///
+    /// This is useful when something the stream depends on has changed, but the stream has no
+    /// way of knowing. The stream will be polled on the next call to
+    /// [`StreamUnordered::poll_next`](Stream::poll_next) (unless it is paused or finished).
+    /// Returns `false` if there is no stream with the given token.
+    pub fn wake(&self, token: usize) -> bool {
+        if token == 0 {
+            return false;
+        }
+
+        match self.by_id.get(token) {
+            Some(&task) => {
+                // we know that by_id only references valid tasks, and the linked
+                // list holds a reference count for it
+                let task = mem::ManuallyDrop::new(unsafe { Arc::from_raw(task) });
+                Task::wake_by_ref(&task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns a [`Waker`](core::task::Waker) that wakes up the stream with the given token.
+    ///
+    /// The waker works just like the one the stream is given when it is polled, and may outlive
+    /// both the stream and the set. Waking it once the stream has been removed does nothing.
+    pub fn waker(&self, token: usize) -> Option<core::task::Waker> {
+        if token == 0 {
+            return None;
+        }
+
+        let task = *self.by_id.get(token)?;
+
+        // we know that by_id only references valid tasks, and the linked list
+        // holds a reference count for it, so we can add another one
+        let task = unsafe {
+            Arc::increment_strong_count(task);
+            Arc::from_raw(task)
+        };
+        Some(Task::waker(task))
+    }
+
+    /// Returns the scheduling state of the stream with the given token.
+    ///
+    /// This is mostly useful for figuring out why a stream is not making progress.
+    pub fn state(&self, token: usize) -> StreamState {
+        if token == 0 {
+            return StreamState::Vacant;
+        }
+
+        match self.by_id.get(token) {
+            // we know that by_id only references valid tasks
+            Some(&task) => unsafe { Task::state(task) },
+            None => StreamState::Vacant,
+        }
+    }
+
+    /// Returns an iterator over the token and scheduling state of each stream in the set.
+    ///
+    /// See [`StreamUnordered::state`].
+    pub fn iter_states(&self) -> IterStates<'_, S> {
+        IterStates {
+            task: self.head_all,
+            len: self.len(),
+            _marker: PhantomData,
+        }
     }
 
     /// Returns a reference to the stream with the given token
-    pub fn get<'a>(&'a self, token: usize) -> Option<&'a S> {
+    pub fn get(&self, token: usize) -> Option<&S> {
         // don't allow access to the 0th task, since it's not a stream
         if token == 0 {
             return None;
@@ -370,54 +969,381 @@ impl<S> StreamUnordered<S> {
     }
 
     /// Returns a reference that allows modifying the stream with the given token.
-    pub fn get_mut<'a>(&'a mut self, token: usize) -> Option<&'a mut S>
+    pub fn get_mut(&mut self, token: usize) -> Option<&mut S>
+    where
+        S: Unpin,
+    {
+        // don't allow access to the 0th task, since it's not a stream
+        if token == 0 {
+            return None;
+        }
+
+        // this is safe for the same reason that IterMut::next is safe
+        Some(unsafe {
+            (*(**self.by_id.get_mut(token)?).stream.get())
+                .as_mut()
+                .unwrap()
+        })
+    }
+
+    /// Returns a pinned reference that allows modifying the stream with the given token.
+    pub fn get_pin_mut(mut self: Pin<&mut Self>, token: usize) -> Option<Pin<&mut S>> {
+        // don't allow access to the 0th task, since it's not a stream
+        if token == 0 {
+            return None;
+        }
+
+        // this is safe for the same reason that IterPinMut::next is safe
+        Some(unsafe {
+            Pin::new_unchecked(
+                (*(**self.by_id.get_mut(token)?).stream.get())
+                    .as_mut()
+                    .unwrap(),
+            )
+        })
+    }
+
+    /// Returns mutable references to several streams at once.
+    ///
+    /// Returns `None` if any of the tokens does not refer to a stream in the set, or if the same
+    /// token appears more than once.
+    pub fn get_many_mut<const N: usize>(&mut self, tokens: [usize; N]) -> Option<[&mut S; N]>
     where
         S: Unpin,
     {
+        Some(
+            Pin::new(self)
+                .get_many_pin_mut(tokens)?
+                .map(Pin::into_inner),
+        )
+    }
+
+    /// Returns pinned references that allow modifying several streams at once.
+    ///
+    /// Returns `None` if any of the tokens does not refer to a stream in the set, or if the same
+    /// token appears more than once.
+    pub fn get_many_pin_mut<const N: usize>(
+        self: Pin<&mut Self>,
+        tokens: [usize; N],
+    ) -> Option<[Pin<&mut S>; N]> {
         // don't allow access to the 0th task, since it's not a stream
+        for (i, &token) in tokens.iter().enumerate() {
+            if token == 0 || !self.by_id.contains(token) || tokens[..i].contains(&token) {
+                return None;
+            }
+        }
+
+        // this is safe for the same reason that get_pin_mut is safe, and since
+        // the tokens are distinct, so are the streams we hand out references to
+        let this = Pin::into_inner(self);
+        Some(tokens.map(|token| unsafe {
+            Pin::new_unchecked((*(*this.by_id[token]).stream.get()).as_mut().unwrap())
+        }))
+    }
+
+    /// Returns an iterator over each stream in the set.
+    pub fn iter(&self) -> Iter<'_, S> {
+        Iter(self.iter_with_tokens())
+    }
+
+    /// Returns an iterator over each stream in the set along with its token.
+    pub fn iter_with_tokens(&self) -> IterWithTokens<'_, S> {
+        IterWithTokens {
+            task: self.head_all,
+            len: self.len(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator that allows modifying each stream in the set, along with its token.
+    pub fn iter_mut_with_tokens(&mut self) -> IterMutWithTokens<'_, S>
+    where
+        S: Unpin,
+    {
+        IterMutWithTokens(Pin::new(self).iter_pin_mut_with_tokens())
+    }
+
+    /// Returns an iterator that allows modifying each stream in the set, along with its token.
+    pub fn iter_pin_mut_with_tokens(self: Pin<&mut Self>) -> IterPinMutWithTokens<'_, S> {
+        IterPinMutWithTokens {
+            task: self.head_all,
+            len: self.len(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the tokens of each stream in the set.
+    pub fn tokens(&self) -> Tokens<'_, S> {
+        Tokens {
+            task: self.head_all,
+            len: self.len(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the tokens of each stream in the set that has yielded `None`.
+    pub fn finished_tokens(&self) -> FilteredTokens<'_, S> {
+        FilteredTokens {
+            task: self.head_all,
+            len: self.len(),
+            finished: true,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the tokens of each stream in the set that has not yet yielded
+    /// `None`.
+    pub fn active_tokens(&self) -> FilteredTokens<'_, S> {
+        FilteredTokens {
+            task: self.head_all,
+            len: self.len(),
+            finished: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator that allows modifying each stream in the set.
+    pub fn iter_mut(&mut self) -> IterMut<'_, S>
+    where
+        S: Unpin,
+    {
+        IterMut(Pin::new(self).iter_pin_mut())
+    }
+
+    /// Returns an iterator that allows modifying each stream in the set.
+    pub fn iter_pin_mut(self: Pin<&mut Self>) -> IterPinMut<'_, S> {
+        IterPinMut {
+            task: self.head_all,
+            len: self.len(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the counters for the stream with the given token.
+    ///
+    /// This only covers streams that are currently in the set, and returns `None` for any other
+    /// token. Once a stream is removed, its counters only show up in the aggregate returned by
+    /// [`StreamUnordered::total_stats`], since its token may be reused for a new stream.
+    ///
+    /// Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self, token: usize) -> Option<StreamStats> {
         if token == 0 {
             return None;
         }
 
-        // this is safe for the same reason that IterMut::next is safe
-        Some(unsafe {
-            (*(**self.by_id.get_mut(token)?).stream.get())
-                .as_mut()
-                .unwrap()
-        })
+        // we know that by_id only references valid tasks, and we're on the thread that owns
+        // StreamUnordered
+        Some(unsafe { (**self.by_id.get(token)?).metrics.snapshot() })
+    }
+
+    /// Returns the counters for the set as a whole.
+    ///
+    /// This adds up the counters of every stream that has ever been in the set, including streams
+    /// that have since been removed, whose individual counters are no longer available through
+    /// [`StreamUnordered::stats`]. Note that this walks all
+    /// the streams in the set, so it takes time linear in [`StreamUnordered::len`].
+    ///
+    /// Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn total_stats(&self) -> Stats {
+        let mut stats = self.retired;
+        let mut task = self.head_all;
+        while !task.is_null() {
+            // we know that head_all only references valid tasks, and we're on the thread that
+            // owns StreamUnordered
+            unsafe {
+                stats.add(&(*task).metrics.snapshot());
+                task = *(*task).next_all.get();
+            }
+        }
+        stats
+    }
+
+    /// Calls `hook` whenever polling a stream takes at least `threshold`.
+    ///
+    /// The hook is given the token of the offending stream and how long its `poll_next` took. It
+    /// is called from within [`StreamUnordered::poll_next`](Stream::poll_next), so it should not
+    /// block either. Setting a new hook replaces the previous one.
+    ///
+    /// Polls are only timed while a hook is set, so this is also what fills in the
+    /// [`poll_time`](StreamStats::poll_time) histograms.
+    ///
+    /// Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn set_slow_poll_hook<F>(&mut self, threshold: std::time::Duration, hook: F)
+    where
+        F: FnMut(usize, std::time::Duration) + Send + 'static,
+    {
+        self.slow_poll = Some((threshold, Box::new(hook)));
+    }
+
+    /// Removes the hook set by [`StreamUnordered::set_slow_poll_hook`].
+    ///
+    /// Only available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn clear_slow_poll_hook(&mut self) {
+        self.slow_poll = None;
+    }
+
+    /// Sets the [`Observer`] that is told about streams being added to and removed from the set.
+    ///
+    /// Setting a new observer replaces the previous one.
+    pub fn set_observer<O>(&mut self, observer: O)
+    where
+        O: Observer + Send + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Removes the [`Observer`] set by [`StreamUnordered::set_observer`].
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    fn notify(&mut self, f: impl FnOnce(&mut dyn Observer)) {
+        if let Some(ref mut observer) = self.observer {
+            f(&mut **observer);
+        }
+    }
+
+    /// Returns the number of streams the set can hold without growing its token table.
+    pub fn capacity(&self) -> usize {
+        // one slot is taken up by the stub
+        self.by_id.capacity() - 1
+    }
+
+    /// Reserves room for at least `additional` more streams in the token table.
+    pub fn reserve(&mut self, additional: usize) {
+        self.by_id.reserve(additional);
+    }
+
+    /// Allocates tasks for `additional` more streams up front, and keeps them for reuse.
+    ///
+    /// This means that the next `additional` pushes will not have to allocate (as long as the
+    /// token table also has room, see [`StreamUnordered::reserve`]). The recycle limit is raised
+    /// to make room for these tasks if necessary (see [`StreamUnordered::set_recycle_limit`]).
+    pub fn reserve_tasks(&mut self, additional: usize) {
+        let target = self.free_tasks.len() + additional;
+        if self.recycle_limit < target {
+            self.set_recycle_limit(target);
+        }
+
+        self.free_tasks.reserve(additional);
+        let queue = Arc::downgrade(&self.ready_to_run_queue);
+        self.free_tasks
+            .extend((0..additional).map(|_| Arc::new(Task::new(queue.clone(), 0))));
+    }
+
+    /// Frees as much memory as possible.
+    ///
+    /// This shrinks the token table as far as the streams in the set allow, and frees any tasks
+    /// kept for reuse. The recycle limit is left as is.
+    pub fn shrink_to_fit(&mut self) {
+        self.by_id.shrink_to_fit();
+        self.free_tasks.clear();
+        self.free_tasks.shrink_to_fit();
+    }
+
+    /// Returns the maximum number of task allocations that are kept around for reuse.
+    ///
+    /// See [`StreamUnordered::set_recycle_limit`].
+    pub fn recycle_limit(&self) -> usize {
+        self.recycle_limit
+    }
+
+    /// Sets the maximum number of task allocations that are kept around for reuse.
+    ///
+    /// Every stream in the set lives in a heap-allocated task, which is normally freed once the
+    /// stream has been removed and any wakers that refer to it have been dropped. If the limit is
+    /// non-zero, up to `limit` such tasks are instead kept in a free list, and reused for
+    /// streams that are pushed later on. This can save a lot of allocator traffic for workloads
+    /// where short-lived streams are added and removed at a high rate.
+    ///
+    /// Note that a task can only be reused once all its wakers are gone. If a stream's wakers
+    /// outlive it (for example in a channel whose other end is still alive), its task is handed
+    /// back to the set when the last of them is dropped, and put in the free list the next time
+    /// the set is polled.
+    ///
+    /// The limit is zero by default. Lowering the limit frees any tasks in excess of it.
+    pub fn set_recycle_limit(&mut self, limit: usize) {
+        self.recycle_limit = limit;
+        self.free_tasks.truncate(limit);
+        self.ready_to_run_queue.recycling.store(limit != 0, SeqCst);
+    }
+
+    /// Returns the maximum number of streams that are polled at the same time, if any.
+    pub fn max_active(&self) -> Option<usize> {
+        self.max_active
+    }
+
+    /// Sets the maximum number of streams that are polled at the same time.
+    ///
+    /// Streams pushed while there are already `max` unfinished streams in the set still get a
+    /// token right away, but wait in a backlog without being polled. Whenever an active stream
+    /// finishes or is removed, the stream that has waited the longest takes its place. This is
+    /// similar to [`buffer_unordered`](futures_util::stream::StreamExt::buffer_unordered), but
+    /// for streams. Streams in the backlog report [`StreamState::Backlogged`].
+    ///
+    /// Raising the limit (or setting it to `None`) promotes streams from the backlog right away.
+    /// Lowering it does not affect streams that are already active.
+    pub fn set_max_active(&mut self, max: Option<usize>) {
+        self.max_active = max;
+        self.promote();
     }
 
-    /// Returns a pinned reference that allows modifying the stream with the given token.
-    pub fn get_pin_mut<'a>(mut self: Pin<&'a mut Self>, token: usize) -> Option<Pin<&'a mut S>> {
-        // don't allow access to the 0th task, since it's not a stream
-        if token == 0 {
-            return None;
-        }
+    /// Returns the number of streams that are waiting for their turn to be polled.
+    ///
+    /// See [`StreamUnordered::set_max_active`].
+    pub fn backlog_len(&self) -> usize {
+        self.backlog.len()
+    }
 
-        // this is safe for the same reason that IterPinMut::next is safe
-        Some(unsafe {
-            Pin::new_unchecked(
-                (*(**self.by_id.get_mut(token)?).stream.get())
-                    .as_mut()
-                    .unwrap(),
-            )
-        })
+    /// Gives up the active slot (or backlog spot) of the stream in `task`, since it has finished
+    /// or is being removed.
+    fn deactivate(&mut self, task: &Task<S>) {
+        // we only ever access the task's flags on the thread that owns StreamUnordered
+        unsafe {
+            if task.has(BACKLOGGED) {
+                task.set(BACKLOGGED, false);
+                let token = task.id;
+                self.backlog.retain(|&t| t != token);
+                return;
+            }
+        }
+        self.active -= 1;
+        self.promote();
     }
 
-    /// Returns an iterator that allows modifying each stream in the set.
-    pub fn iter_mut(&mut self) -> IterMut<'_, S>
-    where
-        S: Unpin,
-    {
-        IterMut(Pin::new(self).iter_pin_mut())
+    /// Moves streams from the backlog into the ready to run queue while there is room.
+    fn promote(&mut self) {
+        while self.max_active.map_or(true, |max| self.active < max) {
+            let token = match self.backlog.pop_front() {
+                Some(token) => token,
+                None => break,
+            };
+            self.active += 1;
+
+            // we know that by_id only references valid tasks, and the flags are
+            // only ever accessed on the thread that owns StreamUnordered.
+            let task = self.by_id[token];
+            unsafe {
+                (*task).set(BACKLOGGED, false);
+                if (*task).has(PARKED) && !(*task).has(PAUSED) {
+                    // just like in `resume`
+                    (*task).set(PARKED, false);
+                    self.ready_to_run_queue.enqueue(task);
+                    self.ready_to_run_queue.waker.wake();
+                }
+            }
+        }
     }
 
-    /// Returns an iterator that allows modifying each stream in the set.
-    pub fn iter_pin_mut(self: Pin<&mut Self>) -> IterPinMut<'_, S> {
-        IterPinMut {
-            task: self.head_all,
-            len: self.len(),
-            _marker: PhantomData,
+    /// Drops a released task, or puts it in the free list if there is room and no-one else
+    /// holds a reference to it.
+    fn recycle_task(&mut self, mut task: Arc<Task<S>>) {
+        if self.free_tasks.len() < self.recycle_limit && Arc::get_mut(&mut task).is_some() {
+            self.free_tasks.push(task);
         }
     }
 
@@ -425,17 +1351,7 @@ impl<S> StreamUnordered<S> {
     /// the `Arc<Task>` or transfers ownership to the ready to run queue.
     /// The task this method is called on must have been unlinked before.
     fn release_task(&mut self, task: Arc<Task<S>>) {
-        self.by_id.remove(task.id);
-
-        // `release_task` must only be called on unlinked tasks
-        unsafe {
-            debug_assert!((*task.next_all.get()).is_null());
-            debug_assert!((*task.prev_all.get()).is_null());
-        }
-
-        // The stream is done, try to reset the queued flag. This will prevent
-        // `wake` from doing any work in the stream
-        let prev = task.queued.swap(true, SeqCst);
+        let prev = self.disown_task(&task);
 
         // Drop the stream, even if it hasn't finished yet. This is safe
         // because we're dropping the stream on the thread that owns
@@ -457,10 +1373,45 @@ impl<S> StreamUnordered<S> {
         // above so all stream `enqueue` operations will not actually
         // enqueue the task, so our task will never see the ready to run queue
         // again. The task itself will be deallocated once all reference counts
-        // have been dropped elsewhere by the various wakers that contain it.
+        // have been dropped elsewhere by the various wakers that contain it,
+        // unless we get to recycle it right away.
         if prev {
             mem::forget(task);
+        } else {
+            self.recycle_task(task);
+        }
+    }
+
+    /// Does the bookkeeping for a task that is leaving the set, except for
+    /// dropping its stream, and sets its queued flag for good.
+    ///
+    /// Returns `true` if the task is still in the ready to run queue, in which
+    /// case the queue must be given the set's reference count.
+    fn disown_task(&mut self, task: &Arc<Task<S>>) -> bool {
+        self.by_id.remove(task.id);
+
+        // we only ever access the task's flags on the thread that owns StreamUnordered
+        if !unsafe { task.has(DONE) } {
+            self.deactivate(task);
+        }
+
+        // Keep the stream's counters around for `total_stats`.
+        #[cfg(feature = "metrics")]
+        self.retired.add(&unsafe { task.metrics.snapshot() });
+
+        // `disown_task` must only be called on unlinked tasks
+        unsafe {
+            debug_assert!((*task.next_all.get()).is_null());
+            debug_assert!((*task.prev_all.get()).is_null());
         }
+
+        // The stream is done, try to reset the queued flag. This will prevent
+        // `wake` from doing any work in the stream
+        let prev = task.queued.swap(true, SeqCst);
+
+        // A parked task is not actually in the ready to run queue, even though
+        // its queued flag is set.
+        prev && !unsafe { task.has(PARKED) }
     }
 
     /// Insert a new task into the internal linked list.
@@ -524,12 +1475,74 @@ where
 /// An event that occurred for a managed stream.
 pub enum StreamYield<S>
 where
-    S: Stream,
+    S: Stream + ?Sized,
 {
     /// The underlying stream produced an item.
     Item(S::Item),
     /// The underlying stream has completed.
     Finished(FinishedStream),
+    /// A stream from the set's source was pushed into the set with the given token.
+    ///
+    /// This is only ever yielded by [`Incoming`].
+    Added(usize),
+}
+
+/// The scheduling state of a stream in a `StreamUnordered`.
+///
+/// See [`StreamUnordered::state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StreamState {
+    /// There is no stream with this token.
+    Vacant,
+    /// The stream is waiting to be woken up.
+    Idle,
+    /// The stream has been woken up, and will be polled on the next call to `poll_next`.
+    Queued,
+    /// The stream has been paused with [`StreamUnordered::pause`].
+    Paused,
+    /// The stream is waiting in the backlog, see [`StreamUnordered::set_max_active`].
+    Backlogged,
+    /// The stream has yielded `None`, but has not been removed.
+    Finished,
+}
+
+/// A set of streams that a [`FinishedStream`] can be removed from.
+///
+/// This is implemented by [`StreamUnordered`] and the other sets in this crate, so that the
+/// [`FinishedStream`]s they yield can be used with any of them.
+pub trait StreamSet {
+    /// The type of the streams in the set.
+    type Stream: ?Sized;
+
+    /// Remove the stream with the given token, dropping it.
+    ///
+    /// See [`StreamUnordered::remove`].
+    fn remove_stream(self: Pin<&mut Self>, token: usize) -> bool;
+}
+
+/// A [`StreamSet`] that streams can also be moved out of.
+pub trait TakeStream: StreamSet
+where
+    Self::Stream: Sized,
+{
+    /// Remove and return the stream with the given token.
+    ///
+    /// See [`StreamUnordered::take`].
+    fn take_stream(self: Pin<&mut Self>, token: usize) -> Option<Self::Stream>;
+}
+
+impl<S> StreamSet for StreamUnordered<S> {
+    type Stream = S;
+
+    fn remove_stream(self: Pin<&mut Self>, token: usize) -> bool {
+        self.remove(token)
+    }
+}
+
+impl<S: Unpin> TakeStream for StreamUnordered<S> {
+    fn take_stream(self: Pin<&mut Self>, token: usize) -> Option<S> {
+        self.take(token)
+    }
 }
 
 /// A stream that has yielded all the items it ever will.
@@ -549,8 +1562,11 @@ impl FinishedStream {
     /// Remove the exhausted stream.
     ///
     /// See [`StreamUnordered::remove`].
-    pub fn remove<S>(self, so: Pin<&mut StreamUnordered<S>>) {
-        so.remove(self.token);
+    pub fn remove<U>(self, so: Pin<&mut U>)
+    where
+        U: StreamSet + ?Sized,
+    {
+        so.remove_stream(self.token);
     }
 
     /// Take the exhausted stream.
@@ -559,11 +1575,12 @@ impl FinishedStream {
     /// been pinned by `StreamUnordered`.
     ///
     /// See [`StreamUnordered::take`].
-    pub fn take<S>(self, so: Pin<&mut StreamUnordered<S>>) -> Option<S>
+    pub fn take<U>(self, so: Pin<&mut U>) -> Option<U::Stream>
     where
-        S: Unpin,
+        U: TakeStream + ?Sized,
+        U::Stream: Sized,
     {
-        so.take(self.token)
+        so.take_stream(self.token)
     }
 
     /// Leave the exhausted stream in the `StreamUnordered`.
@@ -580,27 +1597,75 @@ impl FinishedStream {
     }
 }
 
+/// A stream that was removed from a `StreamUnordered` without being moved.
+///
+/// The stream still lives in the allocation the set kept it in, which this type now owns. See
+/// [`StreamUnordered::into_pinned_streams`].
+pub struct PinnedStream<S> {
+    task: Arc<Task<S>>,
+    _marker: PhantomData<S>,
+}
+
+impl<S> PinnedStream<S> {
+    /// Returns a reference to the stream.
+    pub fn get_ref(&self) -> &S {
+        // Safety: we are the only ones who touch the stream.
+        unsafe { (*self.task.stream.get()).as_ref().unwrap() }
+    }
+
+    /// Returns a pinned reference that allows modifying the stream.
+    pub fn get_pin_mut(&mut self) -> Pin<&mut S> {
+        // Safety: as above, and the stream never moves.
+        unsafe { Pin::new_unchecked((*self.task.stream.get()).as_mut().unwrap()) }
+    }
+}
+
+impl<S> Unpin for PinnedStream<S> {}
+
+impl<S: Stream> Stream for PinnedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().get_pin_mut().poll_next(cx)
+    }
+}
+
+impl<S: Debug> Debug for PinnedStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PinnedStream").field(self.get_ref()).finish()
+    }
+}
+
+impl<S> Drop for PinnedStream<S> {
+    fn drop(&mut self) {
+        // The task may outlive us in a waker, so the stream has to go now.
+        unsafe { *self.task.stream.get() = None };
+    }
+}
+
 impl<S> Debug for StreamYield<S>
 where
-    S: Stream,
+    S: Stream + ?Sized,
     S::Item: Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             StreamYield::Item(ref i) => f.debug_tuple("StreamYield::Item").field(i).finish(),
             StreamYield::Finished(_) => f.debug_tuple("StreamYield::Finished").finish(),
+            StreamYield::Added(t) => f.debug_tuple("StreamYield::Added").field(t).finish(),
         }
     }
 }
 
 impl<S> PartialEq for StreamYield<S>
 where
-    S: Stream,
+    S: Stream + ?Sized,
     S::Item: PartialEq,
 {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (&StreamYield::Item(ref s), &StreamYield::Item(ref o)) => s == o,
+            (StreamYield::Item(s), StreamYield::Item(o)) => s == o,
+            (StreamYield::Added(s), StreamYield::Added(o)) => s == o,
             _ => false,
         }
     }
@@ -610,6 +1675,11 @@ impl<S: Stream> Stream for StreamUnordered<S> {
     type Item = (StreamYield<S>, usize);
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // We stop reading once the streams are being closed.
+        if self.closing {
+            return Poll::Ready(None);
+        }
+
         // Ensure `parent` is correctly set.
         self.ready_to_run_queue.waker.register(cx.waker());
 
@@ -665,15 +1735,31 @@ impl<S: Stream> Stream for StreamUnordered<S> {
                         debug_assert!((*task.next_all.get()).is_null());
                         debug_assert!((*task.prev_all.get()).is_null());
                     }
+                    self.recycle_task(task);
                     continue;
                 }
             };
 
-            // Safety: we only ever access is_done on the thread that owns StreamUnordered.
-            if unsafe { *(*task).is_done.get() } {
+            // Safety: we only ever access the task's flags on the thread that owns StreamUnordered.
+            if unsafe { (*task).has(DONE) } {
                 // This stream has already been polled to completion.
                 // We're keeping it around because the user has not removed it yet.
-                // We can ignore any wake-ups for the Stream.
+                // We can ignore any wake-ups for the Stream, so we park the
+                // task with its queued flag set.
+                #[cfg(feature = "tracing")]
+                tracing::trace!(parent: unsafe { &(*task).span }, "spurious wakeup after finishing");
+                unsafe { (*task).set(PARKED, true) };
+                continue;
+            }
+
+            // Safety: we only ever access the task's flags on the
+            // thread that owns StreamUnordered.
+            if unsafe { (*task).has(PAUSED | BACKLOGGED) } {
+                // The stream was woken up while paused or waiting in the backlog.
+                // We park the task with its queued flag set, which absorbs any
+                // further wake-ups, and `resume` or `promote` puts it back in the
+                // queue.
+                unsafe { (*task).set(PARKED, true) };
                 continue;
             }
 
@@ -708,7 +1794,11 @@ impl<S: Stream> Stream for StreamUnordered<S> {
             impl<S> Drop for Bomb<'_, S> {
                 fn drop(&mut self) {
                     if let Some(task) = self.task.take() {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(parent: &task.span, "stream panicked");
+                        let token = task.id;
                         self.queue.release_task(task);
+                        self.queue.notify(|o| o.on_remove(token));
                     }
                 }
             }
@@ -730,14 +1820,11 @@ impl<S: Stream> Stream for StreamUnordered<S> {
             // These structs will basically just use `S` to size
             // the internal allocation, appropriately accessing fields and
             // deallocating the task if need be.
-            let res = {
-                let waker = Task::waker_ref(bomb.task.as_ref().unwrap());
-                let mut cx = Context::from_waker(&waker);
-
-                // Safety: We won't move the stream ever again
-                let stream = unsafe { Pin::new_unchecked(stream) };
-
-                stream.poll_next(&mut cx)
+            //
+            // Safety: We won't move the stream ever again
+            let res = unsafe {
+                bomb.queue
+                    .poll_task(bomb.task.as_ref().unwrap(), Pin::new_unchecked(stream))
             };
 
             match res {
@@ -753,12 +1840,16 @@ impl<S: Stream> Stream for StreamUnordered<S> {
                     // some work with it (like if it's also a Sink and they need to flush some more
                     // stuff).
 
-                    // Safe as we only ever access is_done on the thread that owns StreamUnordered.
+                    // Safe as we only ever access the task's flags on the thread that owns StreamUnordered.
                     let task = bomb.task.take().unwrap();
                     unsafe {
-                        *task.is_done.get() = true;
+                        task.set(DONE, true);
                     }
+                    bomb.queue.deactivate(&task);
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(parent: &task.span, "stream finished");
                     bomb.queue.link(task);
+                    bomb.queue.notify(|o| o.on_finish(id));
 
                     return Poll::Ready(Some((
                         StreamYield::Finished(FinishedStream { token: id }),
@@ -768,11 +1859,12 @@ impl<S: Stream> Stream for StreamUnordered<S> {
                 Poll::Ready(Some(output)) => {
                     // We're not done with the stream just because it yielded something
                     // We're going to need to poll it again!
-                    Task::wake_by_ref(bomb.task.as_ref().unwrap());
+                    Task::schedule(bomb.task.as_ref().unwrap());
 
                     // And also return it to the task queue
                     let task = bomb.task.take().unwrap();
                     bomb.queue.link(task);
+                    bomb.queue.notify(|o| o.on_item(id));
 
                     return Poll::Ready(Some((StreamYield::Item(output), id)));
                 }
@@ -800,6 +1892,13 @@ impl<S> Drop for StreamUnordered<S> {
         // associated with it. At the same time though there may be tons of
         // wakers flying around which contain `Task<S>` references
         // inside them. We'll let those naturally get deallocated.
+        //
+        // There's no point in recycling the tasks we release along the way.
+        self.set_recycle_limit(0);
+        // Nor in promoting streams that are about to be dropped.
+        self.backlog.clear();
+        self.max_active = None;
+        self.notify(|o| o.on_drop());
         unsafe {
             while !self.head_all.is_null() {
                 let head = self.head_all;
@@ -836,9 +1935,18 @@ impl<S: Stream> FromIterator<S> for StreamUnordered<S> {
     }
 }
 
+impl<S: Unpin> IntoIterator for StreamUnordered<S> {
+    type Item = (usize, S);
+    type IntoIter = IntoStreams<S>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_streams()
+    }
+}
+
 impl<S: Stream> FusedStream for StreamUnordered<S> {
     fn is_terminated(&self) -> bool {
-        self.len == TERMINATED_SENTINEL_LENGTH
+        self.len == TERMINATED_SENTINEL_LENGTH || self.closing
     }
 }
 
@@ -848,23 +1956,19 @@ mod micro {
     use futures_util::{stream, stream::StreamExt};
     use std::pin::Pin;
 
-    #[test]
-    fn no_starvation() {
-        let forever0 = Box::pin(stream::iter(vec![0].into_iter().cycle()));
-        let forever1 = Box::pin(stream::iter(vec![1].into_iter().cycle()));
-        let two = Box::pin(stream::iter(vec![2].into_iter()));
-        let mut s = StreamUnordered::new();
-        let forever0 = s.push(forever0 as Pin<Box<dyn Stream<Item = i32>>>);
-        let forever1 = s.push(forever1 as Pin<Box<dyn Stream<Item = i32>>>);
-        let two = s.push(two as Pin<Box<dyn Stream<Item = i32>>>);
-        let mut rt = tokio::runtime::Builder::new()
-            .basic_scheduler()
-            .build()
-            .unwrap();
-        let mut s = rt.block_on(s.take(100).collect::<Vec<_>>()).into_iter();
+    /// Checks the items collected from a set holding two endless streams and one that yields a
+    /// single `2`, to make sure the short stream got a chance to run.
+    pub(crate) fn check_no_starvation<S>(
+        yields: Vec<(StreamYield<S>, usize)>,
+        forever0: usize,
+        forever1: usize,
+        two: usize,
+    ) where
+        S: Stream<Item = i32> + ?Sized,
+    {
         let mut got_two = false;
         let mut got_two_end = false;
-        while let Some((v, si)) = s.next() {
+        for (v, si) in yields {
             if let StreamYield::Item(v) = v {
                 if si == two {
                     assert_eq!(v, 2);
@@ -885,4 +1989,548 @@ mod micro {
         assert!(got_two, "stream was starved");
         assert!(got_two_end, "stream end was not announced");
     }
+
+    #[test]
+    fn no_starvation() {
+        let forever0 = Box::pin(stream::iter(vec![0].into_iter().cycle()));
+        let forever1 = Box::pin(stream::iter(vec![1].into_iter().cycle()));
+        let two = Box::pin(stream::iter(vec![2]));
+        let mut s = StreamUnordered::new();
+        let forever0 = s.push(forever0 as Pin<Box<dyn Stream<Item = i32>>>);
+        let forever1 = s.push(forever1 as Pin<Box<dyn Stream<Item = i32>>>);
+        let two = s.push(two as Pin<Box<dyn Stream<Item = i32>>>);
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let s = rt.block_on(s.take(100).collect::<Vec<_>>());
+        check_no_starvation(s, forever0, forever1, two);
+    }
+
+    #[test]
+    fn recycle() {
+        use futures_util::future::FutureExt;
+
+        let mut s = StreamUnordered::new();
+        s.set_recycle_limit(1);
+        let a = s.push(stream::pending::<()>());
+        let b = s.push(stream::pending::<()>());
+
+        // poll once so that neither task is left in the ready to run queue
+        assert!(s.next().now_or_never().is_none());
+
+        let task_a = s.by_id[a];
+        assert!(Pin::new(&mut s).remove(a));
+        assert!(Pin::new(&mut s).remove(b));
+        assert_eq!(s.free_tasks.len(), 1);
+
+        let c = s.push(stream::pending());
+        assert!(ptr::eq(s.by_id[c], task_a));
+        assert!(s.free_tasks.is_empty());
+        assert_eq!(s.is_finished(c), Some(false));
+    }
+
+    #[test]
+    fn recycle_held_waker() {
+        use core::task::Waker;
+        use futures_util::future::FutureExt;
+        use std::sync::Mutex;
+
+        // a stream that holds on to its waker, like a channel receiver would
+        let holds_waker = |held: Arc<Mutex<Option<Waker>>>| {
+            stream::poll_fn(move |cx| {
+                *held.lock().unwrap() = Some(cx.waker().clone());
+                Poll::<Option<()>>::Pending
+            })
+        };
+        let held = Arc::new(Mutex::new(None));
+        let mut s = StreamUnordered::new();
+        s.set_recycle_limit(1);
+        let a = s.push(holds_waker(held.clone()));
+        assert!(s.next().now_or_never().is_none());
+
+        // the stream's waker outlives it, so the task can't be reused yet
+        let task_a = s.by_id[a];
+        assert!(Pin::new(&mut s).remove(a));
+        assert!(s.free_tasks.is_empty());
+
+        // once the waker goes away, the task comes back to us
+        held.lock().unwrap().take();
+        assert!(matches!(s.next().now_or_never(), Some(None)));
+        assert_eq!(s.free_tasks.len(), 1);
+        let b = s.push(holds_waker(held.clone()));
+        assert!(ptr::eq(s.by_id[b], task_a));
+    }
+
+    #[test]
+    fn max_active() {
+        use futures_util::future::FutureExt;
+
+        let mut s = StreamUnordered::new();
+        s.set_max_active(Some(1));
+        let a = s.push(stream::iter(vec![1, 2]));
+        let b = s.push(stream::iter(vec![3, 4]));
+        let c = s.push(stream::iter(vec![5, 6]));
+        let d = s.push(stream::iter(vec![7, 8]));
+        assert_eq!(s.backlog_len(), 3);
+        assert_eq!(s.state(b), StreamState::Backlogged);
+
+        // removing a stream in the backlog just takes it out of line
+        assert!(Pin::new(&mut s).remove(c));
+        assert_eq!(s.backlog_len(), 2);
+
+        // the streams are polled one at a time, in the order they were pushed
+        let mut seen = Vec::new();
+        while let Some(Some((y, token))) = s.next().now_or_never() {
+            match y {
+                StreamYield::Item(i) => seen.push((token, i)),
+                StreamYield::Finished(f) => f.remove(Pin::new(&mut s)),
+                StreamYield::Added(_) => unreachable!(),
+            }
+        }
+        assert_eq!(seen, vec![(a, 1), (a, 2), (b, 3), (b, 4), (d, 7), (d, 8)]);
+
+        // raising the limit promotes streams right away
+        let e = s.push(stream::iter(vec![9]));
+        let f = s.push(stream::iter(vec![10]));
+        assert_eq!(s.state(f), StreamState::Backlogged);
+        s.set_max_active(Some(2));
+        assert_eq!(s.state(f), StreamState::Queued);
+        assert!(Pin::new(&mut s).remove(e));
+        assert_eq!(s.backlog_len(), 0);
+        assert_eq!(s.active, 1);
+    }
+
+    #[test]
+    fn pause_and_states() {
+        use futures::channel::mpsc;
+        use futures_util::future::FutureExt;
+
+        let (tx, rx) = mpsc::unbounded();
+        let mut s = StreamUnordered::new();
+        let a = s.push(rx);
+        assert_eq!(s.state(a), StreamState::Queued);
+        assert_eq!(s.state(a + 1), StreamState::Vacant);
+
+        assert!(s.next().now_or_never().is_none());
+        assert_eq!(s.state(a), StreamState::Idle);
+
+        // wake-ups while paused are held back until the stream is resumed
+        assert!(s.pause(a));
+        tx.unbounded_send(1).unwrap();
+        assert!(s.next().now_or_never().is_none());
+        assert_eq!(s.state(a), StreamState::Paused);
+        assert_eq!(s.is_paused(a), Some(true));
+        assert!(s.resume(a));
+        assert_eq!(s.state(a), StreamState::Queued);
+        assert_eq!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Item(1), a)))
+        );
+
+        drop(tx);
+        assert!(s.next().now_or_never().is_some());
+        let (_tx, rx) = mpsc::unbounded();
+        let b = s.push(rx);
+        let mut states: Vec<_> = s.iter_states().collect();
+        states.sort();
+        assert_eq!(
+            states,
+            vec![(a, StreamState::Finished), (b, StreamState::Queued)]
+        );
+    }
+
+    #[test]
+    fn manual_wake() {
+        use futures_util::future::FutureExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let polls = Arc::new(AtomicUsize::new(0));
+        let p = Arc::clone(&polls);
+        let mut s = StreamUnordered::new();
+        let a = s.push(stream::poll_fn(move |_| {
+            p.fetch_add(1, Ordering::SeqCst);
+            Poll::<Option<()>>::Pending
+        }));
+        assert!(s.next().now_or_never().is_none());
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+
+        assert!(s.wake(a));
+        assert!(!s.wake(a + 1));
+        assert_eq!(s.state(a), StreamState::Queued);
+        assert!(s.next().now_or_never().is_none());
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+
+        let waker = s.waker(a).unwrap();
+        std::thread::spawn(move || waker.wake()).join().unwrap();
+        assert!(s.next().now_or_never().is_none());
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+
+        // a waker that outlives its stream does nothing
+        let waker = s.waker(a).unwrap();
+        assert!(Pin::new(&mut s).remove(a));
+        waker.wake();
+        assert_eq!(s.next().now_or_never(), Some(None));
+    }
+
+    #[test]
+    fn poll_single_token() {
+        use futures::channel::mpsc;
+        use futures_util::future::{poll_fn, FutureExt};
+
+        let (tx_a, rx_a) = mpsc::unbounded();
+        let (tx_b, rx_b) = mpsc::unbounded();
+        let mut s = StreamUnordered::new();
+        let a = s.push(rx_a);
+        let b = s.push(rx_b);
+        tx_a.unbounded_send(1).unwrap();
+        tx_b.unbounded_send(2).unwrap();
+
+        let next = |s: &mut StreamUnordered<_>, token| {
+            poll_fn(|cx| Pin::new(&mut *s).poll_next_token(token, cx)).now_or_never()
+        };
+        assert_eq!(next(&mut s, b), Some(Some(StreamYield::Item(2))));
+        assert_eq!(next(&mut s, b), None);
+
+        // b was not consumed from, so a still yields in full
+        assert_eq!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Item(1), a)))
+        );
+        assert!(s.next().now_or_never().is_none());
+
+        drop(tx_b);
+        assert!(matches!(
+            next(&mut s, b),
+            Some(Some(StreamYield::Finished(_)))
+        ));
+        assert_eq!(s.is_finished(b), Some(true));
+        assert_eq!(next(&mut s, b), Some(None));
+        assert_eq!(next(&mut s, b + 1), Some(None));
+        drop(tx_a);
+    }
+
+    #[test]
+    fn iterators() {
+        use futures_util::future::FutureExt;
+
+        let mut s = StreamUnordered::new();
+        let a = s.push(stream::iter(vec![1]));
+        let b = s.push(stream::iter(vec![2, 3]));
+        assert_eq!(s.by_ref().take(4).count().now_or_never(), Some(4));
+
+        let mut tokens: Vec<_> = s.tokens().collect();
+        tokens.sort_unstable();
+        assert_eq!(tokens, vec![a, b]);
+        assert_eq!(s.finished_tokens().collect::<Vec<_>>(), vec![a]);
+        assert_eq!(s.active_tokens().collect::<Vec<_>>(), vec![b]);
+        assert_eq!(s.iter().count(), 2);
+
+        let mut finished = s.finished_tokens();
+        assert_eq!(finished.size_hint(), (0, Some(2)));
+        assert_eq!(finished.by_ref().count(), 1);
+        assert_eq!(finished.size_hint(), (0, Some(0)));
+
+        for (token, stream) in s.iter_with_tokens() {
+            assert!(std::ptr::eq(stream, &s[token]));
+        }
+        for (token, stream) in s.iter_mut_with_tokens() {
+            assert!(token == a || token == b);
+            assert_eq!(stream.size_hint(), (0, Some(0)));
+        }
+        assert_eq!(Pin::new(&mut s).iter_pin_mut_with_tokens().len(), 2);
+    }
+
+    #[test]
+    fn retain_and_drain() {
+        use futures_util::future::FutureExt;
+
+        let mut s = StreamUnordered::new();
+        let a = s.push(stream::iter(vec![1]));
+        let b = s.push(stream::iter(vec![2, 3]));
+        let c = s.push(stream::iter(vec![4, 5, 6]));
+        let d = s.push(stream::iter(vec![7, 8, 9, 10]));
+
+        s.retain(|token, _| token != c);
+        assert_eq!(s.len(), 3);
+        assert!(s.get(c).is_none());
+
+        // a and b run to completion, d has items left
+        assert_eq!(s.by_ref().take(7).count().now_or_never(), Some(7));
+        assert_eq!(s.is_finished(d), Some(false));
+
+        let mut drained = s.drain_finished_streams();
+        drained.sort_by_key(|&(t, _)| t);
+        assert_eq!(
+            drained.iter().map(|&(t, _)| t).collect::<Vec<_>>(),
+            vec![a, b]
+        );
+        assert!(s.drain_finished().is_empty());
+        assert_eq!(s.tokens().collect::<Vec<_>>(), vec![d]);
+
+        assert_eq!(s.by_ref().take(3).count().now_or_never(), Some(3));
+        assert_eq!(s.drain_finished(), vec![d]);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn clear_and_into_streams() {
+        let mut s = StreamUnordered::new();
+        s.push(stream::iter(vec![1]));
+        s.push(stream::iter(vec![2]));
+        s.clear();
+        assert!(s.is_empty());
+        assert_eq!(s.tokens().count(), 0);
+
+        let a = s.push(stream::iter(vec![3]));
+        let b = s.push(stream::iter(vec![4]));
+        let mut streams: Vec<_> = s.into_iter().collect();
+        streams.sort_by_key(|&(t, _)| t);
+        assert_eq!(
+            streams.iter().map(|&(t, _)| t).collect::<Vec<_>>(),
+            vec![a, b]
+        );
+        let items: Vec<_> = streams
+            .into_iter()
+            .map(|(_, s)| futures::executor::block_on_stream(s).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(items, vec![vec![3], vec![4]]);
+    }
+
+    #[test]
+    fn into_pinned_streams() {
+        use futures_util::future::FutureExt;
+
+        // `Once` of an `async` block is not `Unpin`
+        let once = |v| stream::once(async move { v });
+        let mut s = StreamUnordered::new();
+        let a = s.push(once(1));
+        let b = s.push(once(2));
+        s.pause(b);
+
+        // pin the first stream in place by polling it
+        assert!(matches!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Item(1), t))) if t == a
+        ));
+
+        let mut streams: Vec<_> = s.into_pinned_streams().collect();
+        streams.sort_by_key(|&(t, _)| t);
+        assert_eq!(
+            streams.iter().map(|&(t, _)| t).collect::<Vec<_>>(),
+            vec![a, b]
+        );
+        let items: Vec<_> = streams
+            .into_iter()
+            .map(|(_, s)| futures::executor::block_on_stream(s).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(items, vec![vec![], vec![2]]);
+    }
+
+    #[test]
+    fn get_many() {
+        let mut s = StreamUnordered::new();
+        let a = s.push(stream::iter(vec![1]));
+        let b = s.push(stream::iter(vec![2, 3]));
+
+        let [sa, sb] = s.get_many_mut([a, b]).unwrap();
+        std::mem::swap(sa, sb);
+        assert_eq!(s[a].size_hint(), (2, Some(2)));
+        assert_eq!(s[b].size_hint(), (1, Some(1)));
+
+        assert!(s.get_many_mut([a, a]).is_none());
+        assert!(s.get_many_mut([a, b + 1]).is_none());
+        assert!(s.get_many_mut([0]).is_none());
+        assert_eq!(Pin::new(&mut s).get_many_pin_mut([b]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut s = StreamUnordered::with_capacity(8);
+        assert!(s.capacity() >= 8);
+        s.reserve(16);
+        assert!(s.capacity() >= 16);
+
+        s.reserve_tasks(2);
+        assert_eq!(s.recycle_limit(), 2);
+        assert_eq!(s.free_tasks.len(), 2);
+        let task = Arc::as_ptr(&s.free_tasks[1]);
+        let a = s.push(stream::iter(vec![1]));
+        assert!(ptr::eq(s.by_id[a], task));
+        assert_eq!(s.free_tasks.len(), 1);
+
+        assert!(Pin::new(&mut s).remove(a));
+        s.shrink_to_fit();
+        assert!(s.free_tasks.is_empty());
+        assert!(s.capacity() < 16);
+        assert_eq!(s.recycle_limit(), 2);
+    }
+
+    #[test]
+    fn remove_after_spurious_wakeup() {
+        use futures_util::future::FutureExt;
+
+        let mut s = StreamUnordered::new();
+        let tasks = Arc::weak_count(&s.ready_to_run_queue);
+        let waker = Arc::new(AtomicWaker::new());
+        let w = Arc::clone(&waker);
+        let a = s.push(stream::poll_fn(move |cx| -> Poll<Option<()>> {
+            w.register(cx.waker());
+            Poll::Ready(None)
+        }));
+        match s.next().now_or_never() {
+            Some(Some((StreamYield::Finished(f), t))) if t == a => f.keep(),
+            y => panic!("{:?}", y),
+        }
+
+        // the finished stream is woken, and then removed before it is polled again
+        waker.wake();
+        assert!(s.next().now_or_never().is_none());
+        assert!(Pin::new(&mut s).remove(a));
+        assert_eq!(Arc::weak_count(&s.ready_to_run_queue), tasks);
+    }
+
+    #[test]
+    fn observer() {
+        use futures_util::future::FutureExt;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default, Clone)]
+        struct Log(Arc<Mutex<Vec<(&'static str, usize)>>>);
+        impl Observer for Log {
+            fn on_insert(&mut self, token: usize) {
+                self.0.lock().unwrap().push(("insert", token));
+            }
+            fn on_item(&mut self, token: usize) {
+                self.0.lock().unwrap().push(("item", token));
+            }
+            fn on_finish(&mut self, token: usize) {
+                self.0.lock().unwrap().push(("finish", token));
+            }
+            fn on_remove(&mut self, token: usize) {
+                self.0.lock().unwrap().push(("remove", token));
+            }
+            fn on_vacate(&mut self, token: usize) {
+                self.0.lock().unwrap().push(("vacate", token));
+            }
+            fn on_drop(&mut self) {
+                self.0.lock().unwrap().push(("drop", 0));
+            }
+        }
+
+        let log = Log::default();
+        let mut s = StreamUnordered::new();
+        s.set_observer(log.clone());
+
+        let a = s.push(stream::iter(vec![1]));
+        let b = s.stream_entry().token();
+        assert_eq!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Item(1), a)))
+        );
+        assert!(s.next().now_or_never().is_some());
+        let c = s.push(stream::iter(vec![2]));
+        assert_eq!(Pin::new(&mut s).take(a).map(|_| ()), Some(()));
+        assert!(Pin::new(&mut s).remove(c));
+        drop(s);
+
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            vec![
+                ("insert", a),
+                ("vacate", b),
+                ("item", a),
+                ("finish", a),
+                ("insert", c),
+                ("remove", a),
+                ("remove", c),
+                ("drop", 0),
+            ]
+        );
+    }
+
+    #[cfg(all(feature = "tracing", feature = "std"))]
+    #[test]
+    fn tracing_spans() {
+        use futures_util::future::FutureExt;
+        use std::fmt::{Debug, Write};
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        // Logs every span and event as a line of text. Spans are identified by the (1-based)
+        // line they were logged on.
+        #[derive(Clone, Default)]
+        struct Sub(Arc<Mutex<Vec<String>>>);
+
+        struct Fields(String);
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+
+        impl Sub {
+            fn log(&self, what: String, fields: impl FnOnce(&mut Fields)) {
+                let mut f = Fields(what);
+                fields(&mut f);
+                self.0.lock().unwrap().push(f.0);
+            }
+        }
+
+        impl Subscriber for Sub {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                self.log("span".to_string(), |f| span.record(f));
+                Id::from_u64(self.0.lock().unwrap().len() as u64)
+            }
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                self.log(format!("record {}", span.into_u64()), |f| values.record(f));
+            }
+            fn event(&self, event: &Event<'_>) {
+                let parent = event.parent().unwrap().into_u64();
+                self.log(format!("event {}", parent), |f| event.record(f));
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let sub = Sub::default();
+        tracing::subscriber::with_default(sub.clone(), || {
+            let mut s = StreamUnordered::new();
+            let a = s.push_labeled(stream::iter(vec![1]).boxed(), "one");
+            // wakes itself up the first time it is polled, but then has nothing to yield
+            let mut polls = 0;
+            s.push(
+                stream::poll_fn(move |cx| {
+                    polls += 1;
+                    if polls == 1 {
+                        cx.waker().wake_by_ref();
+                    }
+                    Poll::Pending
+                })
+                .boxed(),
+            );
+            assert_eq!(futures::executor::block_on(s.by_ref().take(2).count()), 2);
+            assert!(s.next().now_or_never().is_none());
+            assert!(Pin::new(&mut s).remove(a));
+        });
+
+        assert_eq!(
+            *sub.0.lock().unwrap(),
+            vec![
+                "span token=1",
+                "record 1 label=\"one\"",
+                "span token=2",
+                "event 1 message=stream finished",
+                "event 3 message=spurious wakeup",
+                "event 1 message=stream removed",
+            ]
+        );
+    }
 }
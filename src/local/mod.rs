@@ -0,0 +1,745 @@
+//! A single-threaded variant of `StreamUnordered`.
+//!
+//! This is the same data structure as `StreamUnordered`, except that tasks are reference counted
+//! with `Rc` rather than `Arc`, and that the ready to run queue is a plain linked list rather than
+//! an atomic MPSC queue. This makes wake-ups (and thus polling) cheaper. Wake-ups from other
+//! threads go through a slower path that hands them over to the thread that owns the set.
+
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use core::cell::{Cell, UnsafeCell};
+use core::fmt::{self, Debug};
+use core::iter::FromIterator;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Index, IndexMut};
+use core::pin::Pin;
+use core::ptr;
+use futures_core::stream::{FusedStream, Stream};
+use futures_core::task::{Context, Poll};
+
+use super::{FinishedStream, StreamSet, StreamYield, TakeStream, TERMINATED_SENTINEL_LENGTH};
+
+mod task;
+use self::task::{current_thread, Task};
+
+mod ready_to_run_queue;
+use self::ready_to_run_queue::{ReadyToRunQueue, Remote};
+
+/// A set of streams which may yield items in any order, for use on a single thread.
+///
+/// This behaves exactly like [`StreamUnordered`](crate::StreamUnordered), but avoids all atomic
+/// operations when streams are woken up and polled. This is useful when the set and all the
+/// streams in it live on a single thread, such as inside a `LocalSet`, which also means that
+/// `LocalStreamUnordered` is neither `Send` nor `Sync`.
+///
+/// The wakers that `LocalStreamUnordered` hands to its streams are cheapest to use on the thread
+/// that owns the set. They may still be cloned, woken and dropped on other threads (for example by
+/// an I/O driver running on a thread pool), in which case the operation is handed over to the
+/// owner thread through a mutex, and carried out the next time the set is polled. If a waker
+/// outlives the set on another thread, its stream's bookkeeping is leaked when it is dropped.
+/// Streams that are mostly woken from other threads should use `StreamUnordered` instead.
+#[must_use = "streams do nothing unless polled"]
+pub struct LocalStreamUnordered<S> {
+    ready_to_run_queue: Rc<ReadyToRunQueue<S>>,
+    len: usize,
+    head_all: *const Task<S>,
+    by_id: slab::Slab<*const Task<S>>,
+    remote: Arc<Remote>,
+    thread: usize,
+}
+
+impl<S> Unpin for LocalStreamUnordered<S> {}
+
+/// A handle to an vacant stream slot in a `LocalStreamUnordered`.
+///
+/// `LocalStreamEntry` allows constructing streams that hold the token that they will be assigned.
+#[derive(Debug)]
+pub struct LocalStreamEntry<'a, S> {
+    token: usize,
+    inserted: bool,
+    backref: &'a mut LocalStreamUnordered<S>,
+}
+
+impl<'a, S: 'a> LocalStreamEntry<'a, S> {
+    /// Insert a stream in the slot.
+    ///
+    /// To get the token associated with the stream, use key prior to calling insert.
+    pub fn insert(mut self, stream: S) {
+        self.inserted = true;
+
+        // this is safe because we've held &mut LocalStreamUnordered the entire
+        // time, so the token still points to a valid task, and no-one else is
+        // touching the .stream of it.
+        unsafe {
+            (*(*self.backref.by_id[self.token]).stream.get()) = Some(stream);
+        }
+    }
+
+    /// Return the token associated with this slot.
+    ///
+    /// A stream stored in this slot will be associated with this token.
+    pub fn token(&self) -> usize {
+        self.token
+    }
+}
+
+impl<'a, S: 'a> Drop for LocalStreamEntry<'a, S> {
+    fn drop(&mut self) {
+        if !self.inserted {
+            // undo the insertion
+            let task_ptr = self.backref.by_id[self.token];
+
+            // we know task_ptr points to a valid task, since the
+            // LocalStreamEntry has held the &mut LocalStreamUnordered the
+            // entire time.
+            let task = unsafe { self.backref.unlink(task_ptr) };
+            self.backref.release_task(task);
+        }
+    }
+}
+
+impl<S: Stream> LocalStreamUnordered<S> {
+    /// Constructs a new, empty [`LocalStreamUnordered`].
+    ///
+    /// The returned [`LocalStreamUnordered`] does not contain any streams.
+    /// In this state, [`LocalStreamUnordered::poll_next`](Stream::poll_next) will
+    /// return [`Poll::Ready(None)`](Poll::Ready).
+    pub fn new() -> LocalStreamUnordered<S> {
+        let mut slab = slab::Slab::new();
+
+        // Token 0 is never handed out, to match the tokens of `StreamUnordered`
+        // (where it is taken up by the stub task of the ready to run queue).
+        let _ = slab.insert(ptr::null());
+
+        LocalStreamUnordered {
+            ready_to_run_queue: Rc::new(ReadyToRunQueue::new()),
+            len: 0,
+            head_all: ptr::null(),
+            by_id: slab,
+            remote: Arc::new(Remote::new()),
+            thread: current_thread(),
+        }
+    }
+}
+
+impl<S: Stream> Default for LocalStreamUnordered<S> {
+    fn default() -> LocalStreamUnordered<S> {
+        LocalStreamUnordered::new()
+    }
+}
+
+impl<S> LocalStreamUnordered<S> {
+    /// Returns the number of streams contained in the set.
+    ///
+    /// This represents the total number of in-flight streams.
+    pub fn len(&self) -> usize {
+        if self.len == TERMINATED_SENTINEL_LENGTH {
+            0
+        } else {
+            self.len
+        }
+    }
+
+    /// Returns `true` if the set contains no streams.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 || self.len == TERMINATED_SENTINEL_LENGTH
+    }
+
+    /// Returns a handle to a vacant stream entry allowing for further manipulation.
+    ///
+    /// See [`StreamUnordered::stream_entry`](crate::StreamUnordered::stream_entry).
+    pub fn stream_entry(&mut self) -> LocalStreamEntry<'_, S> {
+        let slot = self.by_id.vacant_entry();
+        let token = slot.key();
+
+        let task = Rc::new(Task {
+            stream: UnsafeCell::new(None),
+            is_done: Cell::new(false),
+            is_parked: Cell::new(false),
+            next_all: Cell::new(ptr::null()),
+            prev_all: Cell::new(ptr::null()),
+            next_ready_to_run: Cell::new(ptr::null()),
+            queued: Cell::new(true),
+            ready_to_run_queue: Rc::downgrade(&self.ready_to_run_queue),
+            remote: Arc::clone(&self.remote),
+            thread: self.thread,
+            id: token,
+        });
+
+        let _ = slot.insert(&*task as *const _);
+
+        // If we've previously marked ourselves as terminated we need to reset
+        // len to 0 to track it correctly
+        if self.len == TERMINATED_SENTINEL_LENGTH {
+            self.len = 0;
+        }
+
+        // Just like for `StreamUnordered`, the linked list takes ownership of
+        // the task's reference count, and we unconditionally enqueue the task
+        // so that its stream gets polled for the first time.
+        let ptr = self.link(task);
+        self.ready_to_run_queue.enqueue(ptr);
+
+        LocalStreamEntry {
+            token,
+            inserted: false,
+            backref: self,
+        }
+    }
+
+    /// Push a stream into the set.
+    ///
+    /// See [`StreamUnordered::push`](crate::StreamUnordered::push).
+    pub fn push(&mut self, stream: S) -> usize {
+        let s = self.stream_entry();
+        let token = s.token();
+        s.insert(stream);
+        token
+    }
+
+    /// Remove a stream from the set.
+    ///
+    /// The stream will be dropped and will no longer yield stream events.
+    pub fn remove(mut self: Pin<&mut Self>, token: usize) -> bool {
+        if token == 0 {
+            return false;
+        }
+
+        let task = if let Some(task) = self.by_id.get(token) {
+            *task
+        } else {
+            return false;
+        };
+
+        // we know that by_id only references valid tasks
+        let task = unsafe { self.unlink(task) };
+        self.release_task(task);
+        true
+    }
+
+    /// Remove and return a stream from the set.
+    ///
+    /// The stream will no longer be polled, and will no longer yield stream events.
+    ///
+    /// Note that since this method moves `S`, which we may have given out a `Pin` to, it requires
+    /// that `S` is `Unpin`.
+    pub fn take(mut self: Pin<&mut Self>, token: usize) -> Option<S>
+    where
+        S: Unpin,
+    {
+        if token == 0 {
+            return None;
+        }
+
+        let task = *self.by_id.get(token)?;
+
+        // we know that by_id only references valid tasks
+        let task = unsafe { self.unlink(task) };
+
+        // Since S: Unpin, it is okay for us to move S.
+        let stream = unsafe { &mut *task.stream.get() }.take();
+
+        self.release_task(task);
+
+        stream
+    }
+
+    /// Returns `true` if the stream with the given token has yielded `None`.
+    pub fn is_finished(&self, token: usize) -> Option<bool> {
+        if token == 0 {
+            return None;
+        }
+
+        // we know that by_id only references valid tasks
+        Some(unsafe { (**self.by_id.get(token)?).is_done.get() })
+    }
+
+    /// Returns a reference to the stream with the given token
+    pub fn get(&self, token: usize) -> Option<&S> {
+        // token 0 is never handed out
+        if token == 0 {
+            return None;
+        }
+
+        // we know that by_id only references valid tasks
+        Some(unsafe { (*(**self.by_id.get(token)?).stream.get()).as_ref().unwrap() })
+    }
+
+    /// Returns a reference that allows modifying the stream with the given token.
+    pub fn get_mut(&mut self, token: usize) -> Option<&mut S>
+    where
+        S: Unpin,
+    {
+        // token 0 is never handed out
+        if token == 0 {
+            return None;
+        }
+
+        // this is safe for the same reason that LocalIterMut::next is safe
+        Some(unsafe {
+            (*(**self.by_id.get_mut(token)?).stream.get())
+                .as_mut()
+                .unwrap()
+        })
+    }
+
+    /// Returns a pinned reference that allows modifying the stream with the given token.
+    pub fn get_pin_mut(mut self: Pin<&mut Self>, token: usize) -> Option<Pin<&mut S>> {
+        // token 0 is never handed out
+        if token == 0 {
+            return None;
+        }
+
+        // this is safe for the same reason that LocalIterPinMut::next is safe
+        Some(unsafe {
+            Pin::new_unchecked(
+                (*(**self.by_id.get_mut(token)?).stream.get())
+                    .as_mut()
+                    .unwrap(),
+            )
+        })
+    }
+
+    /// Returns an iterator that allows modifying each stream in the set.
+    pub fn iter_mut(&mut self) -> LocalIterMut<'_, S>
+    where
+        S: Unpin,
+    {
+        LocalIterMut(Pin::new(self).iter_pin_mut())
+    }
+
+    /// Returns an iterator that allows modifying each stream in the set.
+    pub fn iter_pin_mut(self: Pin<&mut Self>) -> LocalIterPinMut<'_, S> {
+        LocalIterPinMut {
+            task: self.head_all,
+            len: self.len(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Releases the task. It destroys the stream inside and either drops
+    /// the `Rc<Task>` or transfers ownership to the ready to run queue.
+    /// The task this method is called on must have been unlinked before.
+    fn release_task(&mut self, task: Rc<Task<S>>) {
+        self.by_id.remove(task.id);
+
+        // `release_task` must only be called on unlinked tasks
+        debug_assert!(task.next_all.get().is_null());
+        debug_assert!(task.prev_all.get().is_null());
+
+        // See `StreamUnordered::release_task`. A parked task is not actually in
+        // the ready to run queue, even though its queued flag is set.
+        let prev = task.queued.replace(true) && !task.is_parked.get();
+
+        unsafe {
+            // Set to `None` rather than `take()`ing to prevent moving the
+            // stream.
+            *task.stream.get() = None;
+        }
+
+        if prev {
+            mem::forget(task);
+        }
+    }
+
+    /// Carry out what wakers on other threads have left for us to do.
+    fn run_remote(&mut self) {
+        for (task, op) in self.remote.take() {
+            // Safety: we are on the thread that owns the set, and the task is
+            // alive since the waker that left the operation owns a reference
+            // count that is only released by a later operation.
+            unsafe { Task::<S>::run_remote(task, op) };
+        }
+    }
+
+    /// Insert a new task into the internal linked list.
+    fn link(&mut self, task: Rc<Task<S>>) -> *const Task<S> {
+        let ptr = Rc::into_raw(task);
+        unsafe {
+            (*ptr).next_all.set(self.head_all);
+            if !self.head_all.is_null() {
+                (*self.head_all).prev_all.set(ptr);
+            }
+        }
+
+        self.head_all = ptr;
+        self.len += 1;
+        ptr
+    }
+
+    /// Remove the task from the linked list tracking all tasks currently
+    /// managed by `LocalStreamUnordered`.
+    /// This method is unsafe because it has be guaranteed that `task` is a
+    /// valid pointer.
+    unsafe fn unlink(&mut self, task: *const Task<S>) -> Rc<Task<S>> {
+        let task = Rc::from_raw(task);
+
+        let next = task.next_all.replace(ptr::null());
+        let prev = task.prev_all.replace(ptr::null());
+
+        if !next.is_null() {
+            (*next).prev_all.set(prev);
+        }
+
+        if !prev.is_null() {
+            (*prev).next_all.set(next);
+        } else {
+            self.head_all = next;
+        }
+        self.len -= 1;
+        task
+    }
+}
+
+impl<S> Index<usize> for LocalStreamUnordered<S> {
+    type Output = S;
+
+    fn index(&self, stream: usize) -> &Self::Output {
+        self.get(stream).unwrap()
+    }
+}
+
+impl<S> IndexMut<usize> for LocalStreamUnordered<S>
+where
+    S: Unpin,
+{
+    fn index_mut(&mut self, stream: usize) -> &mut Self::Output {
+        self.get_mut(stream).unwrap()
+    }
+}
+
+impl<S> StreamSet for LocalStreamUnordered<S> {
+    type Stream = S;
+
+    fn remove_stream(self: Pin<&mut Self>, token: usize) -> bool {
+        self.remove(token)
+    }
+}
+
+impl<S: Unpin> TakeStream for LocalStreamUnordered<S> {
+    fn take_stream(self: Pin<&mut Self>, token: usize) -> Option<S> {
+        self.take(token)
+    }
+}
+
+impl<S: Stream> Stream for LocalStreamUnordered<S> {
+    type Item = (StreamYield<S>, usize);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Ensure `parent` is correctly set.
+        self.ready_to_run_queue.register(cx.waker());
+        self.remote.waker.register(cx.waker());
+        self.run_remote();
+
+        loop {
+            let task = match self.ready_to_run_queue.dequeue() {
+                None => {
+                    if self.is_empty() {
+                        // We can only consider ourselves terminated once we
+                        // have yielded a `None`
+                        self.len = TERMINATED_SENTINEL_LENGTH;
+                        return Poll::Ready(None);
+                    } else {
+                        return Poll::Pending;
+                    }
+                }
+                Some(task) => task,
+            };
+
+            // Safety:
+            // - `task` is a valid pointer.
+            // - We are the only one that accesses the `UnsafeCell` that
+            //   contains the stream
+            let stream = match unsafe { &mut *(*task).stream.get() } {
+                Some(stream) => stream,
+
+                // The task was released while it was in the ready to run
+                // queue, so we now own its reference count. See the same case
+                // in `StreamUnordered::poll_next`.
+                None => {
+                    let task = unsafe { Rc::from_raw(task) };
+                    debug_assert!(task.next_all.get().is_null());
+                    debug_assert!(task.prev_all.get().is_null());
+                    continue;
+                }
+            };
+
+            if unsafe { (*task).is_done.get() } {
+                // This stream has already been polled to completion.
+                // We're keeping it around because the user has not removed it yet.
+                // We can ignore any wake-ups for the Stream, so we park the
+                // task with its queued flag set.
+                unsafe { (*task).is_parked.set(true) };
+                continue;
+            }
+
+            // Safety: `task` is a valid pointer
+            let task = unsafe { self.unlink(task) };
+
+            // Unset queued flag: This must be done before polling to ensure
+            // that the stream's task gets rescheduled if it sends a wake-up
+            // notification **during** the call to `poll`.
+            let prev = task.queued.replace(false);
+            assert!(prev);
+
+            // See the `Bomb` in `StreamUnordered::poll_next`.
+            struct Bomb<'a, S> {
+                queue: &'a mut LocalStreamUnordered<S>,
+                task: Option<Rc<Task<S>>>,
+            }
+
+            impl<S> Drop for Bomb<'_, S> {
+                fn drop(&mut self) {
+                    if let Some(task) = self.task.take() {
+                        self.queue.release_task(task);
+                    }
+                }
+            }
+
+            let id = task.id;
+            let mut bomb = Bomb {
+                task: Some(task),
+                queue: &mut *self,
+            };
+
+            let res = {
+                let waker = Task::waker_ref(bomb.task.as_ref().unwrap());
+                let mut cx = Context::from_waker(&waker);
+
+                // Safety: We won't move the stream ever again
+                let stream = unsafe { Pin::new_unchecked(stream) };
+
+                stream.poll_next(&mut cx)
+            };
+
+            match res {
+                Poll::Pending => {
+                    let task = bomb.task.take().unwrap();
+                    bomb.queue.link(task);
+                    continue;
+                }
+                Poll::Ready(None) => {
+                    // The stream has completed -- let the user know, but leave
+                    // it to them to remove it.
+                    let task = bomb.task.take().unwrap();
+                    task.is_done.set(true);
+                    bomb.queue.link(task);
+
+                    return Poll::Ready(Some((
+                        StreamYield::Finished(FinishedStream { token: id }),
+                        id,
+                    )));
+                }
+                Poll::Ready(Some(output)) => {
+                    // We're not done with the stream just because it yielded something
+                    // We're going to need to poll it again!
+                    Task::wake_by_ref(bomb.task.as_ref().unwrap());
+
+                    // And also return it to the task queue
+                    let task = bomb.task.take().unwrap();
+                    bomb.queue.link(task);
+
+                    return Poll::Ready(Some((StreamYield::Item(output), id)));
+                }
+            }
+        }
+    }
+}
+
+impl<S> Debug for LocalStreamUnordered<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LocalStreamUnordered {{ ... }}")
+    }
+}
+
+impl<S> Drop for LocalStreamUnordered<S> {
+    fn drop(&mut self) {
+        // See `Drop for StreamUnordered`. Any tasks left in the ready to run
+        // queue will be freed when the queue itself is dropped.
+        self.run_remote();
+        unsafe {
+            while !self.head_all.is_null() {
+                let head = self.head_all;
+                let task = self.unlink(head);
+                self.release_task(task);
+            }
+        }
+    }
+}
+
+impl<S: Stream> FromIterator<S> for LocalStreamUnordered<S> {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+    {
+        let acc = LocalStreamUnordered::new();
+        iter.into_iter().fold(acc, |mut acc, item| {
+            acc.push(item);
+            acc
+        })
+    }
+}
+
+impl<S: Stream> FusedStream for LocalStreamUnordered<S> {
+    fn is_terminated(&self) -> bool {
+        self.len == TERMINATED_SENTINEL_LENGTH
+    }
+}
+
+#[derive(Debug)]
+/// Mutable iterator over all streams in a `LocalStreamUnordered`.
+pub struct LocalIterPinMut<'a, S> {
+    task: *const Task<S>,
+    len: usize,
+    _marker: PhantomData<&'a mut LocalStreamUnordered<S>>,
+}
+
+#[derive(Debug)]
+/// Mutable iterator over all streams in a `LocalStreamUnordered`.
+pub struct LocalIterMut<'a, S: Unpin>(LocalIterPinMut<'a, S>);
+
+impl<'a, S> Iterator for LocalIterPinMut<'a, S> {
+    type Item = Pin<&'a mut S>;
+
+    fn next(&mut self) -> Option<Pin<&'a mut S>> {
+        if self.task.is_null() {
+            return None;
+        }
+        unsafe {
+            let stream = (*(*self.task).stream.get()).as_mut().unwrap();
+            self.task = (*self.task).next_all.get();
+            self.len -= 1;
+            Some(Pin::new_unchecked(stream))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<S> ExactSizeIterator for LocalIterPinMut<'_, S> {}
+
+impl<'a, S: Unpin> Iterator for LocalIterMut<'a, S> {
+    type Item = &'a mut S;
+
+    fn next(&mut self) -> Option<&'a mut S> {
+        self.0.next().map(Pin::get_mut)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<S: Unpin> ExactSizeIterator for LocalIterMut<'_, S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, stream::StreamExt};
+
+    #[test]
+    fn no_starvation() {
+        let forever0 = stream::iter(vec![0].into_iter().cycle()).boxed_local();
+        let forever1 = stream::iter(vec![1].into_iter().cycle()).boxed_local();
+        let two = stream::iter(vec![2]).boxed_local();
+        let mut s = LocalStreamUnordered::new();
+        let forever0 = s.push(forever0);
+        let forever1 = s.push(forever1);
+        let two = s.push(two);
+        let s = futures::executor::block_on(s.take(100).collect::<Vec<_>>());
+        crate::micro::check_no_starvation(s, forever0, forever1, two);
+    }
+
+    #[test]
+    fn wake_from_channel() {
+        let (tx, rx) = futures::channel::mpsc::unbounded::<usize>();
+        let mut s = LocalStreamUnordered::new();
+        let t = s.push(rx);
+        let mut s = futures::executor::block_on_stream(s);
+        tx.unbounded_send(42).unwrap();
+        let (v, si) = s.next().unwrap();
+        assert_eq!(v, StreamYield::Item(42));
+        assert_eq!(si, t);
+        drop(tx);
+        let (v, si) = s.next().unwrap();
+        assert!(matches!(v, StreamYield::Finished(_)));
+        assert_eq!(si, t);
+    }
+
+    #[test]
+    fn wake_from_other_thread() {
+        let (tx, rx) = futures::channel::mpsc::unbounded::<usize>();
+        let mut s = LocalStreamUnordered::new();
+        let t = s.push(rx);
+        let mut s = futures::executor::block_on_stream(s);
+        let sender = std::thread::spawn(move || {
+            tx.unbounded_send(42).unwrap();
+        });
+        let (v, si) = s.next().unwrap();
+        assert_eq!(v, StreamYield::Item(42));
+        assert_eq!(si, t);
+        sender.join().unwrap();
+        let (v, si) = s.next().unwrap();
+        assert!(matches!(v, StreamYield::Finished(_)));
+        assert_eq!(si, t);
+    }
+
+    #[test]
+    fn waker_on_other_thread() {
+        use futures_util::future::FutureExt;
+        use std::sync::Mutex;
+
+        let stored = Arc::new(Mutex::new(None));
+        let st = stored.clone();
+        let mut s = LocalStreamUnordered::new();
+        let a = s.push(stream::poll_fn(move |cx| {
+            *st.lock().unwrap() = Some(cx.waker().clone());
+            Poll::<Option<()>>::Pending
+        }));
+        assert!(s.next().now_or_never().is_none());
+
+        // cloning, waking and dropping the waker elsewhere is handed over to us
+        let waker = stored.lock().unwrap().take().unwrap();
+        std::thread::spawn(move || {
+            let w = waker.clone();
+            drop(waker);
+            w.wake();
+        })
+        .join()
+        .unwrap();
+        assert!(s.next().now_or_never().is_none());
+        assert!(stored.lock().unwrap().is_some());
+
+        // once the stream is gone, so is its task
+        *stored.lock().unwrap() = None;
+        assert!(Pin::new(&mut s).remove(a));
+        assert_eq!(Rc::weak_count(&s.ready_to_run_queue), 0);
+    }
+
+    #[test]
+    fn remove_after_spurious_wakeup() {
+        use futures_util::future::FutureExt;
+        use futures_util::task::AtomicWaker;
+
+        let mut s = LocalStreamUnordered::new();
+        let waker = Rc::new(AtomicWaker::new());
+        let w = Rc::clone(&waker);
+        let a = s.push(stream::poll_fn(move |cx| -> Poll<Option<()>> {
+            w.register(cx.waker());
+            Poll::Ready(None)
+        }));
+        match s.next().now_or_never() {
+            Some(Some((StreamYield::Finished(f), t))) if t == a => f.keep(),
+            y => panic!("{:?}", y),
+        }
+
+        // the finished stream is woken, and then removed before it is polled again
+        waker.wake();
+        assert!(s.next().now_or_never().is_none());
+        assert!(Pin::new(&mut s).remove(a));
+        assert_eq!(Rc::weak_count(&s.ready_to_run_queue), 0);
+    }
+}
@@ -0,0 +1,143 @@
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+use futures_util::task::AtomicWaker;
+use std::sync::Mutex;
+
+use super::task::Task;
+
+/// A single-threaded FIFO queue into which the tasks containing the streams are inserted
+/// whenever the stream inside is scheduled for polling.
+///
+/// Since all wake-ups happen on the thread that owns the `LocalStreamUnordered`, this is just an
+/// intrusive singly-linked list, and we do not need the stub node of the MPSC queue.
+pub(super) struct ReadyToRunQueue<S> {
+    // The waker of the task using `LocalStreamUnordered`.
+    pub(super) waker: RefCell<Option<Waker>>,
+
+    // Head/tail of the readiness queue
+    pub(super) head: Cell<*const Task<S>>,
+    pub(super) tail: Cell<*const Task<S>>,
+}
+
+impl<S> ReadyToRunQueue<S> {
+    pub(super) fn new() -> Self {
+        ReadyToRunQueue {
+            waker: RefCell::new(None),
+            head: Cell::new(ptr::null()),
+            tail: Cell::new(ptr::null()),
+        }
+    }
+
+    /// Push a task to the back of the queue.
+    pub(super) fn enqueue(&self, task: *const Task<S>) {
+        unsafe {
+            debug_assert!((*task).queued.get());
+
+            (*task).next_ready_to_run.set(ptr::null());
+            let tail = self.tail.replace(task);
+            if tail.is_null() {
+                self.head.set(task);
+            } else {
+                (*tail).next_ready_to_run.set(task);
+            }
+        }
+    }
+
+    /// Pop the task at the front of the queue, if any.
+    pub(super) fn dequeue(&self) -> Option<*const Task<S>> {
+        let head = self.head.get();
+        if head.is_null() {
+            return None;
+        }
+
+        let next = unsafe { (*head).next_ready_to_run.replace(ptr::null()) };
+        self.head.set(next);
+        if next.is_null() {
+            self.tail.set(ptr::null());
+        }
+        Some(head)
+    }
+
+    /// Register the waker of the task using `LocalStreamUnordered`.
+    pub(super) fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.borrow_mut();
+        match *slot {
+            Some(ref w) if w.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Notify the task using `LocalStreamUnordered` that there is work to be done.
+    pub(super) fn wake(&self) {
+        if let Some(ref w) = *self.waker.borrow() {
+            w.wake_by_ref();
+        }
+    }
+}
+
+impl<S> Drop for ReadyToRunQueue<S> {
+    fn drop(&mut self) {
+        // Just like for `StreamUnordered`, each task in the ready to run queue
+        // holds a strong reference count owned by the queue, and their streams
+        // have already been dropped by the `LocalStreamUnordered` destructor.
+        while let Some(ptr) = self.dequeue() {
+            drop(unsafe { Rc::from_raw(ptr) });
+        }
+    }
+}
+
+/// Something a waker wanted to do with its task while on a thread other than the one that owns
+/// the `LocalStreamUnordered`.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum RemoteOp {
+    Clone,
+    Wake,
+    Drop,
+}
+
+/// The thread-safe way into a `LocalStreamUnordered`, for wakers used on other threads.
+///
+/// Operations are carried out by the owner thread in the order they were left here, so the
+/// reference counts they touch never drop below what the outstanding wakers own.
+pub(super) struct Remote {
+    // The waker of the task using `LocalStreamUnordered`.
+    pub(super) waker: AtomicWaker,
+
+    // Set whenever `ops` is non-empty, so the owner thread can check without locking.
+    pending: AtomicBool,
+
+    // The operations, along with the task they are for.
+    ops: Mutex<Vec<(usize, RemoteOp)>>,
+}
+
+impl Remote {
+    pub(super) fn new() -> Self {
+        Remote {
+            waker: AtomicWaker::new(),
+            pending: AtomicBool::new(false),
+            ops: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Leave operations for the given task, and notify the task using `LocalStreamUnordered`.
+    pub(super) fn push(&self, task: usize, ops: &[RemoteOp]) {
+        {
+            let mut queue = self.ops.lock().unwrap_or_else(|e| e.into_inner());
+            queue.extend(ops.iter().map(|&op| (task, op)));
+            self.pending.store(true, Ordering::Release);
+        }
+        self.waker.wake();
+    }
+
+    /// Take all the operations left so far.
+    pub(super) fn take(&self) -> Vec<(usize, RemoteOp)> {
+        if !self.pending.swap(false, Ordering::Acquire) {
+            return Vec::new();
+        }
+        core::mem::take(&mut *self.ops.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
@@ -0,0 +1,169 @@
+use alloc::rc::{Rc, Weak};
+use alloc::sync::Arc;
+use core::cell::{Cell, UnsafeCell};
+use core::mem::ManuallyDrop;
+use core::task::{RawWaker, RawWakerVTable, Waker};
+
+use super::ready_to_run_queue::{Remote, RemoteOp};
+use super::ReadyToRunQueue;
+use crate::abort::abort;
+
+pub(super) struct Task<S> {
+    // The stream
+    pub(super) stream: UnsafeCell<Option<S>>,
+
+    // Indicator that the stream has already completed.
+    pub(super) is_done: Cell<bool>,
+
+    // Indicator that the task was taken off the ready to run queue without
+    // being polled (since it was finished), and so is no longer in that queue
+    // even though its `queued` flag is still set.
+    pub(super) is_parked: Cell<bool>,
+
+    // Next pointer for linked list tracking all active tasks
+    pub(super) next_all: Cell<*const Task<S>>,
+
+    // Previous task in linked list tracking all active tasks
+    pub(super) prev_all: Cell<*const Task<S>>,
+
+    // Next pointer in ready to run queue
+    pub(super) next_ready_to_run: Cell<*const Task<S>>,
+
+    // Queue that we'll be enqueued to when woken
+    pub(super) ready_to_run_queue: Weak<ReadyToRunQueue<S>>,
+
+    // Whether or not this task is currently in the ready to run queue
+    pub(super) queued: Cell<bool>,
+
+    // Where wakers used on other threads leave their operations for the owner
+    // thread to carry out
+    pub(super) remote: Arc<Remote>,
+
+    // The thread that owns the `LocalStreamUnordered` this task belongs to
+    pub(super) thread: usize,
+
+    // A unique identifier for this stream
+    pub(super) id: usize,
+}
+
+/// Returns an identifier for the current thread that is unique among all live threads.
+///
+/// This is the address of a thread-local, which is cheaper to get at than
+/// `std::thread::current().id()` as it does not touch any reference counts.
+pub(super) fn current_thread() -> usize {
+    std::thread_local!(static MARKER: u8 = const { 0 });
+    MARKER.with(|m| m as *const u8 as usize)
+}
+
+impl<S> Task<S> {
+    /// Enqueue this task in its ready to run queue, and notify the parent task.
+    ///
+    /// This must only be called on the thread that owns the task.
+    pub(super) fn wake_by_ref(this: &Rc<Task<S>>) {
+        let inner = match this.ready_to_run_queue.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        // Same as for `StreamUnordered`, except that there is no one else who
+        // could be racing with us to set the `queued` flag.
+        if !this.queued.replace(true) {
+            inner.enqueue(&**this);
+            inner.wake();
+        }
+    }
+
+    /// Returns a waker reference for this task without cloning the `Rc`.
+    pub(super) fn waker_ref(this: &Rc<Task<S>>) -> ManuallyDrop<Waker> {
+        // Safety: the returned waker does not own a reference count, which is
+        // why it is wrapped in `ManuallyDrop`. Any clones made from it _do_
+        // own a reference count.
+        ManuallyDrop::new(unsafe { Waker::from_raw(Self::raw_waker(Rc::as_ptr(this))) })
+    }
+
+    fn raw_waker(this: *const Task<S>) -> RawWaker {
+        RawWaker::new(this as *const (), Self::VTABLE)
+    }
+
+    const VTABLE: &'static RawWakerVTable = &RawWakerVTable::new(
+        Self::clone_waker,
+        Self::wake,
+        Self::wake_by_ref_raw,
+        Self::drop_waker,
+    );
+
+    // The `Waker` type is `Send + Sync`, so there is nothing stopping a stream
+    // from handing its waker to another thread, such as an I/O driver. Since
+    // our reference counts and flags are not atomic, such a waker must not
+    // touch any of them. Instead, it leaves what it wanted to do with the
+    // task's `Remote`, and the owner thread carries it out the next time the
+    // set is polled. The task stays alive in the meantime, since the waker
+    // still owns its reference count.
+    unsafe fn on_owner_thread(data: *const ()) -> bool {
+        (*(data as *const Task<S>)).thread == current_thread()
+    }
+
+    unsafe fn remote(data: *const (), ops: &[RemoteOp]) {
+        (*(data as *const Task<S>)).remote.push(data as usize, ops);
+    }
+
+    /// Carries out an operation left by a waker on another thread.
+    ///
+    /// This must only be called on the thread that owns the task.
+    pub(super) unsafe fn run_remote(data: usize, op: RemoteOp) {
+        let data = data as *const ();
+        match op {
+            RemoteOp::Clone => drop(Self::clone_waker(data)),
+            RemoteOp::Wake => Self::wake_by_ref_raw(data),
+            RemoteOp::Drop => Self::drop_waker(data),
+        }
+    }
+
+    unsafe fn clone_waker(data: *const ()) -> RawWaker {
+        if Self::on_owner_thread(data) {
+            Rc::increment_strong_count(data as *const Task<S>);
+        } else {
+            Self::remote(data, &[RemoteOp::Clone]);
+        }
+        Self::raw_waker(data as *const Task<S>)
+    }
+
+    unsafe fn wake(data: *const ()) {
+        if Self::on_owner_thread(data) {
+            let this = Rc::from_raw(data as *const Task<S>);
+            Self::wake_by_ref(&this);
+        } else {
+            Self::remote(data, &[RemoteOp::Wake, RemoteOp::Drop]);
+        }
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        if Self::on_owner_thread(data) {
+            let this = ManuallyDrop::new(Rc::from_raw(data as *const Task<S>));
+            Self::wake_by_ref(&this);
+        } else {
+            Self::remote(data, &[RemoteOp::Wake]);
+        }
+    }
+
+    unsafe fn drop_waker(data: *const ()) {
+        if Self::on_owner_thread(data) {
+            drop(Rc::from_raw(data as *const Task<S>));
+        } else {
+            Self::remote(data, &[RemoteOp::Drop]);
+        }
+    }
+}
+
+impl<S> Drop for Task<S> {
+    fn drop(&mut self) {
+        // Wakers may keep a task alive for longer than the `LocalStreamUnordered`
+        // it belongs to, so just like for `StreamUnordered` we must make sure
+        // the stream itself was dropped by its owner.
+        unsafe {
+            if (*self.stream.get()).is_some() {
+                abort("stream still here when dropping");
+            }
+        }
+    }
+}
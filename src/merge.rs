@@ -0,0 +1,180 @@
+//! A k-way merge of sorted streams.
+
+use super::{StreamUnordered, StreamYield};
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt::{self, Debug};
+use core::pin::Pin;
+use futures_core::stream::{FusedStream, Stream};
+use futures_core::task::{Context, Poll};
+
+/// A set of streams whose items are yielded in ascending order of a key.
+///
+/// Each stream's items are expected to already be in order. The set holds on to the next item
+/// (the head) of every stream, and only yields the head with the smallest key once every
+/// unfinished stream has one. The stream whose head was yielded is then polled again, while all
+/// the others are paused. Items with equal keys are yielded in token order.
+///
+/// Streams are removed from the set as soon as they finish.
+pub struct StreamOrderedMerge<S, F, K>
+where
+    S: Stream,
+{
+    streams: StreamUnordered<S>,
+    heads: Vec<Option<S::Item>>,
+    order: BinaryHeap<Reverse<(K, usize)>>,
+    // the number of streams in the set without a head
+    waiting: usize,
+    key: F,
+}
+
+impl<S, F, K> StreamOrderedMerge<S, F, K>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Ord,
+{
+    /// Constructs a new, empty [`StreamOrderedMerge`] that orders items by the key returned by
+    /// `key`.
+    pub fn new(key: F) -> Self {
+        StreamOrderedMerge {
+            streams: StreamUnordered::new(),
+            heads: Vec::new(),
+            order: BinaryHeap::new(),
+            waiting: 0,
+            key,
+        }
+    }
+
+    /// Push a stream into the set, and return its token.
+    ///
+    /// No more items are yielded until the new stream has yielded its first item (or finished).
+    pub fn push(&mut self, stream: S) -> usize {
+        let token = self.streams.push(stream);
+        if self.heads.len() <= token {
+            self.heads.resize_with(token + 1, || None);
+        }
+        self.waiting += 1;
+        token
+    }
+
+    /// Returns the number of streams in the set.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Returns `true` if the set contains no streams.
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Returns a reference to the buffered head item of the stream with the given token, if any.
+    pub fn peek(&self, token: usize) -> Option<&S::Item> {
+        self.heads.get(token)?.as_ref()
+    }
+}
+
+impl<S, F, K> Stream for StreamOrderedMerge<S, F, K>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Ord,
+{
+    type Item = (S::Item, usize);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.waiting == 0 {
+                if let Some(Reverse((_, token))) = this.order.pop() {
+                    let item = this.heads[token].take().expect("ordered token has a head");
+                    this.streams.resume(token);
+                    this.waiting += 1;
+                    return Poll::Ready(Some((item, token)));
+                }
+            }
+
+            match Pin::new(&mut this.streams).poll_next(cx) {
+                Poll::Ready(Some((StreamYield::Item(item), token))) => {
+                    // hold on to the stream's head until it is the smallest one
+                    this.streams.pause(token);
+                    this.order.push(Reverse(((this.key)(&item), token)));
+                    this.heads[token] = Some(item);
+                    this.waiting -= 1;
+                }
+                Poll::Ready(Some((StreamYield::Finished(f), _))) => {
+                    f.remove(Pin::new(&mut this.streams));
+                    this.waiting -= 1;
+                }
+                Poll::Ready(Some((StreamYield::Added(_), _))) => {
+                    unreachable!("no source is attached")
+                }
+                Poll::Ready(None) => {
+                    // Streams are only removed once they have finished, which
+                    // they cannot do while holding a head.
+                    debug_assert!(this.order.is_empty());
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S, F, K> FusedStream for StreamOrderedMerge<S, F, K>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Ord,
+{
+    fn is_terminated(&self) -> bool {
+        // Every head belongs to a stream that is still in the set, so
+        // there is nothing left to yield once the set has terminated.
+        self.streams.is_terminated()
+    }
+}
+
+impl<S: Stream, F, K> Unpin for StreamOrderedMerge<S, F, K> {}
+
+impl<S: Stream, F, K> Debug for StreamOrderedMerge<S, F, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StreamOrderedMerge {{ ... }}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures_util::future::FutureExt;
+    use futures_util::stream::{self, StreamExt};
+
+    #[test]
+    fn merge() {
+        let mut s = StreamOrderedMerge::new(|&(ts, _): &(u32, char)| ts);
+        let a = s.push(stream::iter(vec![(1, 'a'), (4, 'a'), (7, 'a')]).boxed());
+        let b = s.push(stream::iter(vec![(2, 'b'), (4, 'b')]).boxed());
+        let (tx, rx) = mpsc::unbounded();
+        let c = s.push(rx.boxed());
+
+        // we can't yield anything until every stream has a head
+        assert!(s.next().now_or_never().is_none());
+        assert!(!s.is_terminated());
+        assert_eq!(s.peek(a), Some(&(1, 'a')));
+        assert_eq!(s.peek(c), None);
+
+        tx.unbounded_send((3, 'c')).unwrap();
+        assert_eq!(s.next().now_or_never(), Some(Some(((1, 'a'), a))));
+        assert_eq!(s.next().now_or_never(), Some(Some(((2, 'b'), b))));
+        assert_eq!(s.next().now_or_never(), Some(Some(((3, 'c'), c))));
+
+        // c has no head again, and equal keys come out in token order
+        tx.unbounded_send((8, 'c')).unwrap();
+        drop(tx);
+        let rest: Vec<_> = s.by_ref().map(|(i, _)| i).collect().now_or_never().unwrap();
+        assert_eq!(rest, vec![(4, 'a'), (4, 'b'), (7, 'a'), (8, 'c')]);
+        assert!(s.is_empty());
+        assert!(s.is_terminated());
+    }
+}
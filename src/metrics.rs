@@ -0,0 +1,330 @@
+//! Counters that track how the streams in a `StreamUnordered` are being driven.
+//!
+//! Only compiled in with the `metrics` feature.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use core::task::Poll;
+use std::time::{Duration, Instant};
+
+/// The number of buckets in a [`PollHistogram`].
+const BUCKETS: usize = 16;
+
+/// A histogram of how long calls to a stream's `poll_next` took.
+///
+/// The buckets are spaced by powers of two. The first bucket counts polls that took less than a
+/// microsecond, the next one those that took less than two, then less than four, and so on. The
+/// last bucket counts every poll that did not fit in the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PollHistogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl PollHistogram {
+    /// Returns the number of polls that fell in each bucket.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Returns the exclusive upper bound on the poll durations counted in the given bucket.
+    ///
+    /// Returns `None` for the last bucket, which has no upper bound.
+    pub fn bucket_bound(&self, bucket: usize) -> Option<Duration> {
+        if bucket + 1 >= BUCKETS {
+            None
+        } else {
+            Some(Duration::from_micros(1 << bucket))
+        }
+    }
+
+    /// Returns the total number of polls recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    fn record(&mut self, took: Duration) {
+        let micros = took.as_micros();
+        let bucket = if micros == 0 {
+            0
+        } else {
+            (128 - micros.leading_zeros()) as usize
+        };
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+
+    fn add(&mut self, other: &PollHistogram) {
+        for (b, o) in self.buckets.iter_mut().zip(&other.buckets) {
+            *b += o;
+        }
+    }
+}
+
+/// A snapshot of the counters for a single stream in a `StreamUnordered`.
+///
+/// See [`StreamUnordered::stats`](crate::StreamUnordered::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StreamStats {
+    /// The number of items the stream has yielded.
+    pub items: u64,
+    /// The number of times the stream has been polled.
+    pub polls: u64,
+    /// The number of times polling the stream returned `Poll::Pending`.
+    pub pending_polls: u64,
+    /// The number of wake-ups the stream has received.
+    pub wakeups: u64,
+    /// The number of those wake-ups that did nothing since the stream was already queued to be
+    /// polled.
+    ///
+    /// Wake-ups of a stream that is finished, paused or waiting for room to be polled are not
+    /// counted here, since they do not lead to a poll either way.
+    pub coalesced_wakeups: u64,
+    /// When the stream yielded `None`, if it has.
+    pub finished_at: Option<Instant>,
+    /// How long the stream's polls took.
+    ///
+    /// Polls are only timed while a slow-poll hook is set, see
+    /// [`StreamUnordered::set_slow_poll_hook`](crate::StreamUnordered::set_slow_poll_hook).
+    pub poll_time: PollHistogram,
+}
+
+/// A snapshot of the counters for a `StreamUnordered` as a whole.
+///
+/// This covers every stream that has ever been in the set, including those that have since been
+/// removed. See [`StreamUnordered::total_stats`](crate::StreamUnordered::total_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of items yielded across all streams.
+    pub items: u64,
+    /// The number of times any stream has been polled.
+    pub polls: u64,
+    /// The number of times polling a stream returned `Poll::Pending`.
+    pub pending_polls: u64,
+    /// The number of wake-ups received across all streams.
+    pub wakeups: u64,
+    /// The number of those wake-ups that did nothing since the stream was already queued to be
+    /// polled.
+    ///
+    /// Wake-ups of a stream that is finished, paused or waiting for room to be polled are not
+    /// counted here, since they do not lead to a poll either way.
+    pub coalesced_wakeups: u64,
+    /// The number of streams that have yielded `None`.
+    pub finished: u64,
+    /// How long polls took across all streams, while a slow-poll hook was set.
+    pub poll_time: PollHistogram,
+}
+
+impl Stats {
+    pub(crate) fn add(&mut self, s: &StreamStats) {
+        self.items += s.items;
+        self.polls += s.polls;
+        self.pending_polls += s.pending_polls;
+        self.wakeups += s.wakeups;
+        self.coalesced_wakeups += s.coalesced_wakeups;
+        self.finished += u64::from(s.finished_at.is_some());
+        self.poll_time.add(&s.poll_time);
+    }
+}
+
+/// The counters kept in each task.
+///
+/// Wake-ups may happen on any thread, so those counters are atomic. The rest are only ever
+/// touched by the thread that owns the set, just like `Task::is_done`.
+pub(crate) struct TaskMetrics {
+    items: UnsafeCell<u64>,
+    polls: UnsafeCell<u64>,
+    pending_polls: UnsafeCell<u64>,
+    wakeups: AtomicU64,
+    coalesced_wakeups: AtomicU64,
+    // Mirrors the task's `PARKED` flag, so that wake-ups from other threads can tell a task that
+    // is actually in the ready to run queue from one whose queued flag is only set to absorb
+    // wake-ups.
+    parked: AtomicBool,
+    finished_at: UnsafeCell<Option<Instant>>,
+    poll_time: UnsafeCell<PollHistogram>,
+}
+
+impl TaskMetrics {
+    pub(crate) fn new() -> Self {
+        TaskMetrics {
+            items: UnsafeCell::new(0),
+            polls: UnsafeCell::new(0),
+            pending_polls: UnsafeCell::new(0),
+            wakeups: AtomicU64::new(0),
+            coalesced_wakeups: AtomicU64::new(0),
+            parked: AtomicBool::new(false),
+            finished_at: UnsafeCell::new(None),
+            poll_time: UnsafeCell::new(PollHistogram::default()),
+        }
+    }
+
+    /// Record a wake-up, given whether the task's queued flag was already set.
+    pub(crate) fn woken(&self, was_queued: bool) {
+        self.wakeups.fetch_add(1, Relaxed);
+        // A parked task is finished, paused or backlogged, and its queued flag being set does not
+        // mean that a poll is already on its way.
+        if was_queued && !self.parked.load(Relaxed) {
+            self.coalesced_wakeups.fetch_add(1, Relaxed);
+        }
+    }
+
+    pub(crate) fn set_parked(&self, parked: bool) {
+        self.parked.store(parked, Relaxed);
+    }
+
+    /// Record the result of polling the stream, and how long that took if the poll was timed.
+    ///
+    /// Must only be called from the thread that owns the set.
+    pub(crate) unsafe fn polled<T>(&self, res: &Poll<Option<T>>, took: Option<Duration>) {
+        *self.polls.get() += 1;
+        if let Some(took) = took {
+            (*self.poll_time.get()).record(took);
+        }
+        match res {
+            Poll::Pending => *self.pending_polls.get() += 1,
+            Poll::Ready(Some(_)) => *self.items.get() += 1,
+            Poll::Ready(None) => *self.finished_at.get() = Some(Instant::now()),
+        }
+    }
+
+    /// Must only be called from the thread that owns the set.
+    pub(crate) unsafe fn snapshot(&self) -> StreamStats {
+        StreamStats {
+            items: *self.items.get(),
+            polls: *self.polls.get(),
+            pending_polls: *self.pending_polls.get(),
+            wakeups: self.wakeups.load(Relaxed),
+            coalesced_wakeups: self.coalesced_wakeups.load(Relaxed),
+            finished_at: *self.finished_at.get(),
+            poll_time: *self.poll_time.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamUnordered;
+    use futures_util::future::FutureExt;
+    use futures_util::{stream, stream::StreamExt};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::Poll;
+    use std::time::Duration;
+
+    #[test]
+    fn counts() {
+        // a stream that wakes itself up twice the first time it is polled, and then never again
+        let mut first = true;
+        let twice = stream::poll_fn(move |cx| {
+            if std::mem::replace(&mut first, false) {
+                cx.waker().wake_by_ref();
+                cx.waker().wake_by_ref();
+            }
+            Poll::<Option<i32>>::Pending
+        });
+
+        let mut s = StreamUnordered::new();
+        let a = s.push(twice.boxed());
+        let b = s.push(stream::iter(vec![1, 2]).boxed());
+
+        assert_eq!(s.by_ref().take(3).count().now_or_never(), Some(3));
+        assert!(s.next().now_or_never().is_none());
+
+        let sa = s.stats(a).unwrap();
+        assert_eq!(sa.items, 0);
+        assert_eq!(sa.polls, 2);
+        assert_eq!(sa.pending_polls, 2);
+        assert_eq!(sa.wakeups, 2);
+        assert_eq!(sa.coalesced_wakeups, 1);
+        assert_eq!(sa.finished_at, None);
+
+        let sb = s.stats(b).unwrap();
+        assert_eq!(sb.items, 2);
+        assert_eq!(sb.polls, 3);
+        assert_eq!(sb.pending_polls, 0);
+        assert_eq!(sb.wakeups, 0);
+        assert!(sb.finished_at.is_some());
+
+        // removed streams still count towards the totals
+        assert!(Pin::new(&mut s).remove(b));
+        assert_eq!(s.stats(b), None);
+        let st = s.total_stats();
+        assert_eq!(st.items, 2);
+        assert_eq!(st.polls, 5);
+        assert_eq!(st.pending_polls, 2);
+        assert_eq!(st.wakeups, 2);
+        assert_eq!(st.coalesced_wakeups, 1);
+        assert_eq!(st.finished, 1);
+        // polls aren't timed without a slow-poll hook
+        assert_eq!(st.poll_time.count(), 0);
+    }
+
+    #[test]
+    fn parked_wakeups() {
+        let mut s = StreamUnordered::new();
+        let a = s.push(stream::pending::<()>().boxed());
+        let b = s.push(stream::empty::<()>().boxed());
+        assert!(matches!(s.next().now_or_never(), Some(Some(_))));
+        assert!(s.next().now_or_never().is_none());
+
+        // the first wake-up of each queues it, and the next poll parks it
+        assert!(s.pause(a));
+        assert!(s.wake(a));
+        assert!(s.wake(b));
+        assert!(s.next().now_or_never().is_none());
+
+        // wake-ups of parked streams aren't coalesced, since no poll is coming
+        assert!(s.wake(a));
+        assert!(s.wake(b));
+        for t in [a, b] {
+            let st = s.stats(t).unwrap();
+            assert_eq!(st.wakeups, 2);
+            assert_eq!(st.coalesced_wakeups, 0);
+        }
+
+        // once resumed, the stream is queued for real again
+        assert!(s.resume(a));
+        assert!(s.wake(a));
+        assert_eq!(s.stats(a).unwrap().coalesced_wakeups, 1);
+    }
+
+    #[test]
+    fn histogram() {
+        let mut h = PollHistogram::default();
+        h.record(Duration::from_nanos(10));
+        h.record(Duration::from_micros(1));
+        h.record(Duration::from_micros(3));
+        h.record(Duration::from_secs(10));
+        assert_eq!(&h.buckets()[..4], &[1, 1, 1, 0]);
+        assert_eq!(h.buckets()[BUCKETS - 1], 1);
+        assert_eq!(h.count(), 4);
+        assert_eq!(h.bucket_bound(2), Some(Duration::from_micros(4)));
+        assert_eq!(h.bucket_bound(BUCKETS - 1), None);
+    }
+
+    #[test]
+    fn slow_poll() {
+        let slow = stream::poll_fn(|_| {
+            std::thread::sleep(Duration::from_millis(20));
+            Poll::Ready(Some(()))
+        });
+
+        let mut s = StreamUnordered::new();
+        s.push(stream::repeat(()).boxed());
+        let slow = s.push(slow.boxed());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = Arc::clone(&seen);
+        s.set_slow_poll_hook(Duration::from_millis(10), move |token, took| {
+            assert!(took >= Duration::from_millis(10));
+            seen2.lock().unwrap().push(token);
+        });
+
+        assert_eq!(s.by_ref().take(4).count().now_or_never(), Some(4));
+        assert_eq!(*seen.lock().unwrap(), vec![slow, slow]);
+        assert_eq!(s.stats(slow).unwrap().poll_time.count(), 2);
+    }
+}
@@ -0,0 +1,46 @@
+/// Callbacks for the lifecycle events of the streams in a `StreamUnordered`.
+///
+/// This is useful for keeping side tables keyed by stream token in sync with the set, since all
+/// the ways a token can come and go are funneled through one place. Install an observer with
+/// [`StreamUnordered::set_observer`](crate::StreamUnordered::set_observer).
+///
+/// All methods do nothing by default.
+pub trait Observer {
+    /// A stream was inserted with the given token, either through
+    /// [`StreamUnordered::push`](crate::StreamUnordered::push) or [`StreamEntry::insert`](crate::StreamEntry::insert).
+    fn on_insert(&mut self, token: usize) {
+        let _ = token;
+    }
+
+    /// The stream with the given token yielded an item.
+    fn on_item(&mut self, token: usize) {
+        let _ = token;
+    }
+
+    /// The stream with the given token yielded `None`.
+    ///
+    /// The stream (and its token) is still in the set until it is removed.
+    fn on_finish(&mut self, token: usize) {
+        let _ = token;
+    }
+
+    /// The stream with the given token was removed from the set, and the token may be reused.
+    ///
+    /// This is called for both [`StreamUnordered::remove`](crate::StreamUnordered::remove) and
+    /// [`StreamUnordered::take`](crate::StreamUnordered::take), as well as when the stream
+    /// panics while being polled.
+    fn on_remove(&mut self, token: usize) {
+        let _ = token;
+    }
+
+    /// A [`StreamEntry`](crate::StreamEntry) for the given token was dropped without a stream
+    /// being inserted, so the token may be reused.
+    fn on_vacate(&mut self, token: usize) {
+        let _ = token;
+    }
+
+    /// The set is being dropped, along with all the streams that are still in it.
+    ///
+    /// [`Observer::on_remove`] is _not_ called for each of those streams.
+    fn on_drop(&mut self) {}
+}
@@ -1,44 +1,59 @@
 use alloc::sync::Arc;
 use core::cell::UnsafeCell;
 use core::ptr;
-use core::sync::atomic::AtomicPtr;
 use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use core::sync::atomic::{AtomicBool, AtomicPtr};
 use futures_util::task::AtomicWaker;
 
-use super::abort::abort;
-use super::task::Task;
+use crate::abort::abort;
 
-pub(super) enum Dequeue<S> {
-    Data(*const Task<S>),
+/// A task that can be placed in a `ReadyToRunQueue`.
+pub(crate) trait Node: Sized {
+    /// Next pointer in ready to run queue
+    fn next_ready_to_run(&self) -> &AtomicPtr<Self>;
+
+    /// Whether or not this task is currently in the ready to run queue
+    fn queued(&self) -> &AtomicBool;
+
+    /// Drop the reference count held by the ready to run queue.
+    unsafe fn release(task: *const Self);
+}
+
+pub(crate) enum Dequeue<N> {
+    Data(*const N),
     Empty,
     Inconsistent,
 }
 
-pub(super) struct ReadyToRunQueue<S> {
+pub(crate) struct ReadyToRunQueue<N: Node> {
     // The waker of the task using `StreamUnordered`.
-    pub(super) waker: AtomicWaker,
+    pub(crate) waker: AtomicWaker,
 
     // Head/tail of the readiness queue
-    pub(super) head: AtomicPtr<Task<S>>,
-    pub(super) tail: UnsafeCell<*const Task<S>>,
-    pub(super) stub: Arc<Task<S>>,
+    pub(crate) head: AtomicPtr<N>,
+    pub(crate) tail: UnsafeCell<*const N>,
+    pub(crate) stub: Arc<N>,
+
+    // Whether tasks should be handed back to the owner for reuse once their
+    // last waker is dropped.
+    pub(crate) recycling: AtomicBool,
 }
 
 /// An MPSC queue into which the tasks containing the streams are inserted
 /// whenever the stream inside is scheduled for polling.
-impl<S> ReadyToRunQueue<S> {
+impl<N: Node> ReadyToRunQueue<N> {
     /// The enqueue function from the 1024cores intrusive MPSC queue algorithm.
-    pub(super) fn enqueue(&self, task: *const Task<S>) {
+    pub(crate) fn enqueue(&self, task: *const N) {
         unsafe {
-            debug_assert!((*task).queued.load(Relaxed));
+            debug_assert!((*task).queued().load(Relaxed));
 
             // This action does not require any coordination
-            (*task).next_ready_to_run.store(ptr::null_mut(), Relaxed);
+            (*task).next_ready_to_run().store(ptr::null_mut(), Relaxed);
 
             // Note that these atomic orderings come from 1024cores
             let task = task as *mut _;
             let prev = self.head.swap(task, AcqRel);
-            (*prev).next_ready_to_run.store(task, Release);
+            (*prev).next_ready_to_run().store(task, Release);
         }
     }
 
@@ -46,9 +61,9 @@ impl<S> ReadyToRunQueue<S> {
     ///
     /// Note that this is unsafe as it required mutual exclusion (only one
     /// thread can call this) to be guaranteed elsewhere.
-    pub(super) unsafe fn dequeue(&self) -> Dequeue<S> {
+    pub(crate) unsafe fn dequeue(&self) -> Dequeue<N> {
         let mut tail = *self.tail.get();
-        let mut next = (*tail).next_ready_to_run.load(Acquire);
+        let mut next = (*tail).next_ready_to_run().load(Acquire);
 
         if tail == self.stub() {
             if next.is_null() {
@@ -57,7 +72,7 @@ impl<S> ReadyToRunQueue<S> {
 
             *self.tail.get() = next;
             tail = next;
-            next = (*next).next_ready_to_run.load(Acquire);
+            next = (*next).next_ready_to_run().load(Acquire);
         }
 
         if !next.is_null() {
@@ -66,13 +81,13 @@ impl<S> ReadyToRunQueue<S> {
             return Dequeue::Data(tail);
         }
 
-        if self.head.load(Acquire) as *const _ != tail {
+        if !ptr::eq(self.head.load(Acquire), tail) {
             return Dequeue::Inconsistent;
         }
 
         self.enqueue(self.stub());
 
-        next = (*tail).next_ready_to_run.load(Acquire);
+        next = (*tail).next_ready_to_run().load(Acquire);
 
         if !next.is_null() {
             *self.tail.get() = next;
@@ -82,12 +97,12 @@ impl<S> ReadyToRunQueue<S> {
         Dequeue::Inconsistent
     }
 
-    pub(super) fn stub(&self) -> *const Task<S> {
+    pub(crate) fn stub(&self) -> *const N {
         &*self.stub
     }
 }
 
-impl<S> Drop for ReadyToRunQueue<S> {
+impl<N: Node> Drop for ReadyToRunQueue<N> {
     fn drop(&mut self) {
         // Once we're in the destructor for `Inner<S>` we need to clear out
         // the ready to run queue of tasks if there's anything left in there.
@@ -101,7 +116,7 @@ impl<S> Drop for ReadyToRunQueue<S> {
                 match self.dequeue() {
                     Dequeue::Empty => break,
                     Dequeue::Inconsistent => abort("inconsistent in drop"),
-                    Dequeue::Data(ptr) => drop(Arc::from_raw(ptr)),
+                    Dequeue::Data(ptr) => N::release(ptr),
                 }
             }
         }
@@ -0,0 +1,168 @@
+//! Numbering the items yielded by a `StreamUnordered`.
+
+use super::{StreamUnordered, StreamYield};
+use core::pin::Pin;
+use futures_core::stream::{FusedStream, Stream};
+use futures_core::task::{Context, Poll};
+
+/// Where an item yielded by a [`Sequenced`] set falls, both within its stream and within the set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Sequence {
+    /// The number of items the stream yielded before this one.
+    ///
+    /// This starts at zero for every stream, including for a stream that reuses the token of one
+    /// that was removed.
+    pub seq: u64,
+    /// The number of items any stream in the set yielded before this one.
+    pub arrival: u64,
+}
+
+/// A [`StreamUnordered`] that numbers the items it yields.
+///
+/// This yields the same events as the underlying set, but items also come with their
+/// [`Sequence`], which makes it possible to detect gaps or to restore order after the fan-in.
+/// The counters are kept with each stream in the set, so this does not allocate.
+///
+/// The set only keeps count while it is wrapped in a `Sequenced`, and the counting starts over
+/// from zero every time it is wrapped.
+///
+/// Created with [`StreamUnordered::sequenced`].
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Sequenced<S> {
+    inner: StreamUnordered<S>,
+}
+
+impl<S> StreamUnordered<S> {
+    /// Numbers the items yielded by the set, see [`Sequenced`].
+    pub fn sequenced(mut self) -> Sequenced<S> {
+        self.arrivals = Some(0);
+        let mut task = self.head_all;
+        while !task.is_null() {
+            // we know that head_all only references valid tasks, and seq is
+            // only ever accessed on the thread that owns StreamUnordered.
+            unsafe {
+                *(*task).seq.get() = 0;
+                task = *(*task).next_all.get();
+            }
+        }
+        Sequenced { inner: self }
+    }
+}
+
+impl<S> Sequenced<S> {
+    /// Returns a reference to the underlying set.
+    pub fn get_ref(&self) -> &StreamUnordered<S> {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying set.
+    ///
+    /// Streams pushed through this reference are numbered just the same.
+    pub fn get_mut(&mut self) -> &mut StreamUnordered<S> {
+        &mut self.inner
+    }
+
+    /// Returns the underlying set, which stops numbering its items.
+    pub fn into_inner(mut self) -> StreamUnordered<S> {
+        self.inner.arrivals = None;
+        self.inner
+    }
+}
+
+impl<S: Stream> Stream for Sequenced<S> {
+    /// The event and token yielded by the set, along with the [`Sequence`] for items.
+    type Item = (StreamYield<S>, usize, Option<Sequence>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (y, token) = match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(next)) => next,
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let sequence = if let StreamYield::Item(_) = y {
+            // The stream that yielded the item is still in the set, and its
+            // counter has already been bumped for it. We know that by_id only
+            // references valid tasks, and seq is only ever accessed on the
+            // thread that owns StreamUnordered.
+            let seq = unsafe { *(*self.inner.by_id[token]).seq.get() };
+            let arrivals = self.inner.arrivals.expect("Sequenced keeps count");
+            Some(Sequence {
+                seq: seq - 1,
+                arrival: arrivals - 1,
+            })
+        } else {
+            None
+        };
+        Poll::Ready(Some((y, token, sequence)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Stream> FusedStream for Sequenced<S> {
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::FutureExt;
+    use futures_util::stream::{self, StreamExt};
+
+    #[test]
+    fn sequenced() {
+        let mut s = StreamUnordered::new().sequenced();
+        let a = s.get_mut().push(stream::iter(vec![10, 11, 12]));
+        let b = s.get_mut().push(stream::iter(vec![20, 21]));
+
+        let mut items = Vec::new();
+        while let Some(Some((y, token, sequence))) = s.next().now_or_never() {
+            match y {
+                StreamYield::Item(i) => items.push((token, i, sequence.unwrap())),
+                _ => assert_eq!(sequence, None),
+            }
+        }
+
+        // each stream counts on its own, and arrivals count across the set
+        let seqs: Vec<_> = items
+            .iter()
+            .filter(|&&(t, _, _)| t == a)
+            .map(|&(_, i, s)| (i, s.seq))
+            .collect();
+        assert_eq!(seqs, vec![(10, 0), (11, 1), (12, 2)]);
+        let seqs: Vec<_> = items
+            .iter()
+            .filter(|&&(t, _, _)| t == b)
+            .map(|&(_, i, s)| (i, s.seq))
+            .collect();
+        assert_eq!(seqs, vec![(20, 0), (21, 1)]);
+        let arrivals: Vec<_> = items.iter().map(|&(_, _, s)| s.arrival).collect();
+        assert_eq!(arrivals, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn only_counts_while_wrapped() {
+        let mut s = StreamUnordered::new();
+        let a = s.push(stream::iter(vec![10, 11, 12]));
+
+        // items yielded before wrapping are not counted
+        assert!(s.next().now_or_never().is_some());
+        assert_eq!(s.arrivals, None);
+        let mut s = s.sequenced();
+        assert!(matches!(
+            s.next().now_or_never(),
+            Some(Some((StreamYield::Item(11), t, Some(Sequence { seq: 0, arrival: 0 })))) if t == a
+        ));
+
+        // and the unwrapped set stops counting
+        let mut s = s.into_inner();
+        assert!(s.next().now_or_never().is_some());
+        assert_eq!(s.arrivals, None);
+    }
+}
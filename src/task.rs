@@ -1,18 +1,45 @@
 use alloc::sync::{Arc, Weak};
 use core::cell::UnsafeCell;
+use core::ptr;
 use core::sync::atomic::Ordering::SeqCst;
 use core::sync::atomic::{AtomicBool, AtomicPtr};
 
 use super::abort::abort;
-use super::ReadyToRunQueue;
-use futures_util::task::{waker_ref, ArcWake, WakerRef};
+#[cfg(feature = "metrics")]
+use super::metrics::TaskMetrics;
+use super::ready_to_run_queue::{Node, ReadyToRunQueue};
+use super::StreamState;
+use core::mem::ManuallyDrop;
+use core::task::{RawWaker, RawWakerVTable, Waker};
+
+// Indicator that the stream has already completed.
+pub(super) const DONE: u8 = 1 << 0;
+
+// Indicator that the stream should not be polled until it is resumed.
+pub(super) const PAUSED: u8 = 1 << 1;
+
+// Indicator that the task was taken off the ready to run queue without being
+// polled (since it was finished or paused), and so is no longer in that queue
+// even though its `queued` flag is still set.
+pub(super) const PARKED: u8 = 1 << 2;
+
+// Indicator that the stream's sink has been closed (or failed to close) by
+// `StreamUnordered::poll_close_all`.
+pub(super) const CLOSED: u8 = 1 << 3;
+
+// Indicator that the stream is waiting in `StreamUnordered::backlog` until
+// there is room for it to be polled.
+pub(super) const BACKLOGGED: u8 = 1 << 4;
 
 pub(super) struct Task<S> {
     // The stream
     pub(super) stream: UnsafeCell<Option<S>>,
 
-    // Indicator that the stream has already completed.
-    pub(super) is_done: UnsafeCell<bool>,
+    // The `DONE`, `PAUSED`, `PARKED`, `CLOSED` and `BACKLOGGED` flags.
+    flags: UnsafeCell<u8>,
+
+    // The number of items the stream has yielded, used for `Sequence::seq`.
+    pub(super) seq: UnsafeCell<u64>,
 
     // Next pointer for linked list tracking all active tasks
     pub(super) next_all: UnsafeCell<*const Task<S>>,
@@ -24,13 +51,26 @@ pub(super) struct Task<S> {
     pub(super) next_ready_to_run: AtomicPtr<Task<S>>,
 
     // Queue that we'll be enqueued to when woken
-    pub(super) ready_to_run_queue: Weak<ReadyToRunQueue<S>>,
+    pub(super) ready_to_run_queue: Weak<ReadyToRunQueue<Task<S>>>,
 
     // Whether or not this task is currently in the ready to run queue
     pub(super) queued: AtomicBool,
 
     // A unique identifier for this stream
     pub(super) id: usize,
+
+    // Counters for `StreamUnordered::stats`
+    #[cfg(feature = "metrics")]
+    pub(super) metrics: TaskMetrics,
+
+    // Span that the stream is polled in
+    #[cfg(feature = "tracing")]
+    pub(super) span: tracing::Span,
+
+    // Whether the stream's waker was used since it was last polled, so that
+    // we can tell when a wake-up turned out to be spurious.
+    #[cfg(feature = "tracing")]
+    pub(super) woken: AtomicBool,
 }
 
 // `Task` can be sent across threads safely because it ensures that
@@ -41,11 +81,76 @@ pub(super) struct Task<S> {
 unsafe impl<S> Send for Task<S> {}
 unsafe impl<S> Sync for Task<S> {}
 
-impl<S> ArcWake for Task<S> {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
+impl<S> Node for Task<S> {
+    fn next_ready_to_run(&self) -> &AtomicPtr<Self> {
+        &self.next_ready_to_run
+    }
+
+    fn queued(&self) -> &AtomicBool {
+        &self.queued
+    }
+
+    unsafe fn release(task: *const Self) {
+        drop(Arc::from_raw(task));
+    }
+}
+
+impl<S> Task<S> {
+    /// Constructs a task without a stream, which is queued as far as wakers are concerned.
+    pub(super) fn new(ready_to_run_queue: Weak<ReadyToRunQueue<Task<S>>>, id: usize) -> Self {
+        Task {
+            stream: UnsafeCell::new(None),
+            flags: UnsafeCell::new(0),
+            seq: UnsafeCell::new(0),
+            next_all: UnsafeCell::new(ptr::null()),
+            prev_all: UnsafeCell::new(ptr::null()),
+            next_ready_to_run: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(true),
+            ready_to_run_queue,
+            id,
+            #[cfg(feature = "metrics")]
+            metrics: TaskMetrics::new(),
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+            #[cfg(feature = "tracing")]
+            woken: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether any of the given flags are set.
+    ///
+    /// Must only be called from the thread that owns the `StreamUnordered`.
+    pub(super) unsafe fn has(&self, flags: u8) -> bool {
+        *self.flags.get() & flags != 0
+    }
+
+    /// Sets or clears the given flags.
+    ///
+    /// Must only be called from the thread that owns the `StreamUnordered`.
+    pub(super) unsafe fn set(&self, flags: u8, on: bool) {
+        if on {
+            *self.flags.get() |= flags;
+        } else {
+            *self.flags.get() &= !flags;
+        }
+        #[cfg(feature = "metrics")]
+        if flags & PARKED != 0 {
+            self.metrics.set_parked(on);
+        }
+    }
+
+    /// Clears all flags, for a task that is about to be reused.
+    pub(super) fn reset_flags(&mut self) {
+        *self.flags.get_mut() = 0;
+    }
+
+    /// Enqueue this task in the ready to run queue unless it is already there.
+    ///
+    /// Returns `true` if the task was already queued.
+    pub(super) fn schedule(arc_self: &Arc<Self>) -> bool {
         let inner = match arc_self.ready_to_run_queue.upgrade() {
             Some(inner) => inner,
-            None => return,
+            None => return false,
         };
 
         // It's our job to enqueue this task it into the ready to run queue. To
@@ -65,13 +170,101 @@ impl<S> ArcWake for Task<S> {
             inner.enqueue(&**arc_self);
             inner.waker.wake();
         }
+        prev
+    }
+
+    /// Returns the scheduling state of the given task.
+    ///
+    /// Must only be called from the thread that owns the `StreamUnordered`.
+    pub(super) unsafe fn state(task: *const Self) -> StreamState {
+        if (*task).has(DONE) {
+            StreamState::Finished
+        } else if (*task).has(PAUSED) {
+            StreamState::Paused
+        } else if (*task).has(BACKLOGGED) {
+            StreamState::Backlogged
+        } else if (*task).queued.load(SeqCst) && !(*task).has(PARKED) {
+            StreamState::Queued
+        } else {
+            StreamState::Idle
+        }
+    }
+
+    /// Handle a wake-up for this task.
+    pub(super) fn wake_by_ref(arc_self: &Arc<Self>) {
+        #[cfg(feature = "tracing")]
+        arc_self.woken.store(true, SeqCst);
+        let _was_queued = Task::schedule(arc_self);
+        #[cfg(feature = "metrics")]
+        arc_self.metrics.woken(_was_queued);
+    }
+
+    /// Returns a waker for this task.
+    pub(super) fn waker(this: Arc<Task<S>>) -> Waker {
+        // Safety: the vtable functions uphold the `RawWaker` contract, and the
+        // data pointer owns the reference count we were given.
+        unsafe { Waker::from_raw(Self::raw_waker(Arc::into_raw(this))) }
     }
-}
 
-impl<S> Task<S> {
     /// Returns a waker reference for this task without cloning the Arc.
-    pub(super) fn waker_ref<'a>(this: &'a Arc<Task<S>>) -> WakerRef<'a> {
-        waker_ref(this)
+    pub(super) fn waker_ref(this: &Arc<Task<S>>) -> ManuallyDrop<Waker> {
+        // Safety: the returned waker does not own a reference count, which is
+        // why it is wrapped in `ManuallyDrop`. Any clones made from it _do_
+        // own a reference count.
+        ManuallyDrop::new(unsafe { Waker::from_raw(Self::raw_waker(Arc::as_ptr(this))) })
+    }
+
+    // We build the waker by hand rather than going through `ArcWake`, since
+    // that requires `Task<S>: 'static`. That isn't necessary here, since the
+    // waker never touches the stream (see the comment on `Drop for Task`).
+    fn raw_waker(this: *const Task<S>) -> RawWaker {
+        RawWaker::new(this as *const (), Self::VTABLE)
+    }
+
+    const VTABLE: &'static RawWakerVTable = &RawWakerVTable::new(
+        Self::clone_waker,
+        Self::wake,
+        Self::wake_by_ref_raw,
+        Self::drop_waker,
+    );
+
+    unsafe fn clone_waker(data: *const ()) -> RawWaker {
+        Arc::increment_strong_count(data as *const Task<S>);
+        Self::raw_waker(data as *const Task<S>)
+    }
+
+    unsafe fn wake(data: *const ()) {
+        let this = Arc::from_raw(data as *const Task<S>);
+        Self::wake_by_ref(&this);
+        Self::drop_ref(this);
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        let this = ManuallyDrop::new(Arc::from_raw(data as *const Task<S>));
+        Self::wake_by_ref(&this);
+    }
+
+    unsafe fn drop_waker(data: *const ()) {
+        Self::drop_ref(Arc::from_raw(data as *const Task<S>));
+    }
+
+    /// Drops a reference to this task that was held by a waker.
+    ///
+    /// If that was the last reference, the task has already been released by
+    /// its `StreamUnordered` (which holds a reference for as long as the stream
+    /// is in the set). If the set recycles tasks, we then hand the task back
+    /// through the ready to run queue, and the set will put it in its free list
+    /// when it comes across it. The task's `queued` flag was left set when it was
+    /// released, so no-one else will try to enqueue it.
+    fn drop_ref(mut this: Arc<Task<S>>) {
+        if Arc::get_mut(&mut this).is_none() {
+            return;
+        }
+        if let Some(inner) = this.ready_to_run_queue.upgrade() {
+            if inner.recycling.load(SeqCst) {
+                inner.enqueue(Arc::into_raw(this));
+            }
+        }
     }
 }
 
@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_pattern_matching, clippy::unwrap_or_default)]
+
 use futures::prelude::*;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::future::Future;
@@ -81,6 +83,7 @@ impl Future for Echoer {
                     f.remove(Pin::new(&mut self.inputs));
                     continue;
                 }
+                Poll::Ready(Some((StreamYield::Added(_), _))) => unreachable!(),
                 Poll::Ready(None) => unreachable!(),
                 Poll::Pending => break,
             }
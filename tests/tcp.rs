@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_pattern_matching, clippy::unwrap_or_default)]
+
 use async_bincode::*;
 use futures::prelude::*;
 use std::collections::{HashMap, HashSet, VecDeque};
@@ -98,6 +100,7 @@ impl Future for Echoer {
                     f.keep();
                     continue;
                 }
+                Poll::Ready(Some((StreamYield::Added(_), _))) => unreachable!(),
                 Poll::Ready(None) => {
                     // no connections yet
                     break;
@@ -113,6 +116,61 @@ impl Future for Echoer {
     }
 }
 
+/// Like [`Echoer`], but lets [`DuplexUnordered`] do the queueing and flushing.
+struct DuplexEchoer {
+    incoming: tokio::net::TcpListener,
+    inputs: DuplexUnordered<
+        AsyncBincodeStream<tokio::net::TcpStream, String, String, AsyncDestination>,
+        String,
+    >,
+}
+
+impl DuplexEchoer {
+    pub fn new(on: tokio::net::TcpListener) -> Self {
+        DuplexEchoer {
+            incoming: on,
+            inputs: DuplexUnordered::new(16),
+        }
+    }
+
+    fn try_new(&mut self, cx: &mut Context<'_>) -> bincode::Result<()> {
+        while let Poll::Ready(Some(stream)) =
+            Pin::new(&mut self.incoming.incoming()).poll_next(cx)?
+        {
+            let tcp = AsyncBincodeStream::from(stream).for_async();
+            self.inputs.push(tcp);
+        }
+        Ok(())
+    }
+}
+
+impl Future for DuplexEchoer {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // see if there are any new connections
+        self.try_new(cx).unwrap();
+
+        // see if there's new input for us, and send stuff that needs to be sent
+        loop {
+            match Pin::new(&mut self.inputs).poll_next(cx) {
+                Poll::Ready(Some((DuplexYield::Item(packet), sender))) => {
+                    self.inputs.send_to(sender, packet.unwrap()).unwrap();
+                }
+                Poll::Ready(Some((DuplexYield::SendError(e), _))) => panic!("{:?}", e),
+                Poll::Ready(Some((DuplexYield::Closed, _))) => continue,
+                Poll::Ready(Some((DuplexYield::Overflow(_), _))) => unreachable!(),
+                Poll::Ready(None) => {
+                    // no connections yet
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
 #[tokio::test]
 async fn oneshot() {
     let on = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -156,3 +214,23 @@ async fn close_early() {
     let r: String = r.next().await.unwrap().unwrap();
     assert_eq!(r, String::from("hello world"));
 }
+
+#[tokio::test]
+async fn duplex() {
+    let on = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = on.local_addr().unwrap();
+    tokio::spawn(DuplexEchoer::new(on));
+
+    let s = tokio::net::TcpStream::connect(&addr).await.unwrap();
+    let mut s = AsyncBincodeStream::from(s).for_async();
+    s.send(String::from("hello world")).await.unwrap();
+    let r: String = s.next().await.unwrap().unwrap();
+    assert_eq!(r, String::from("hello world"));
+
+    // the echo is still sent after we stop writing
+    let (mut r, mut w) = s.tcp_split();
+    w.send(String::from("goodbye world")).await.unwrap();
+    w.close().await.unwrap();
+    let r: String = r.next().await.unwrap().unwrap();
+    assert_eq!(r, String::from("goodbye world"));
+}